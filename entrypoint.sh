@@ -7,9 +7,5 @@ else
     echo "netchat.db already exists"
 fi
 
-if [ ! -f /app/config.toml ]; then
-  cp /app/config.toml.example /app/config.toml
-  echo "Created config.toml from config.toml.example"
-fi
-
-exec netchat-server
+# without /app/config.toml the server uses its built-in defaults and NETCHAT_* environment variables
+exec netchat-server "$@"
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::blocks::without_blockers;
+use crate::conn_handler::send_to_clients;
+use crate::db::{count_unread, get_message, mark_read, StoredMessage};
+use crate::state::{get_active_users, get_chat_rooms, get_rooms_of, sessions_of, Client};
+use crate::validators::validate_username;
+
+// a client typing away only has its TYPING forwarded this often per conversation
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+
+/// Keeps a connection from flooding others with `TYPING` events.
+#[derive(Default)]
+pub struct TypingThrottle {
+    last_forwarded: HashMap<String, Instant>,
+}
+
+impl TypingThrottle {
+    pub fn allow(&mut self, target: &str, now: Instant) -> bool {
+        match self.last_forwarded.get(target) {
+            Some(last) if now.duration_since(*last) < TYPING_THROTTLE => false,
+            _ => {
+                self.last_forwarded.insert(target.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+// unsolicited frames would confuse protocol 1 clients, and a user's own devices know they are typing
+fn others_on_protocol_2(clients: Vec<Arc<Client>>, username: &str) -> Vec<Arc<Client>> {
+    clients.into_iter()
+        .filter(|client| client.listener.sends_events() && client.username().as_deref() != Some(username))
+        .collect()
+}
+
+/// Forwards `TYPING:<username>:<target>` to the other side of a direct conversation or to the members of a room.
+pub async fn forward_typing(client: &Arc<Client>, username: &str, target: &str) {
+    let recipients = if target == "global" {
+        get_active_users().read().await.values().flatten().cloned().collect()
+    } else if let Some(members) = get_chat_rooms().read().await.get(target) {
+        // only members get to announce themselves in a room
+        if !members.iter().any(|member| Arc::ptr_eq(member, client)) {
+            return;
+        }
+        members.clone()
+    } else if validate_username(target) {
+        sessions_of(target).await
+    } else {
+        return;
+    };
+    let recipients = without_blockers(&others_on_protocol_2(recipients, username), username);
+    send_to_clients(&recipients, &format!("TYPING:{}:{}\n", username, target));
+}
+
+/// The conversation a message is filed under for `username`, None if they were never meant to see it.
+/// Direct messages are filed under the other user, everything else under its recipient.
+pub(crate) fn conversation_of(message: &StoredMessage, username: &str, rooms: &[String]) -> Option<String> {
+    if message.recipient == username {
+        Some(message.sender.clone())
+    } else if message.sender == username || message.recipient == "global" || rooms.contains(&message.recipient) {
+        Some(message.recipient.clone())
+    } else {
+        None
+    }
+}
+
+/// Handles `READ:<message-id>`, moves the read marker of its conversation and tells the sender of a direct message.
+/// Returns the reply for the client, if there is one.
+pub async fn read(client: &Arc<Client>, username: &str, message_id: &str) -> Option<String> {
+    let invalid = Some(format!("READ_INVALID:{}\n", message_id));
+    let Ok(id) = message_id.parse::<i64>() else {
+        return invalid;
+    };
+    let message = match get_message(id) {
+        Ok(Some(message)) => message,
+        Ok(None) => return invalid,
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to load message {}: {}", id, e);
+            return invalid;
+        }
+    };
+    let Some(conversation) = conversation_of(&message, username, &get_rooms_of(client).await) else {
+        return invalid;
+    };
+    if let Err(e) = mark_read(username, &conversation, id) {
+        tracing::error!(target: "db", "Failed to store read marker of {}: {}", username, e);
+        return invalid;
+    }
+
+    if message.recipient == username && message.sender != username {
+        let receipt = format!("READ:{}:{}\n", username, id);
+        send_to_clients(&others_on_protocol_2(sessions_of(&message.sender).await, username), &receipt);
+    }
+    None
+}
+
+/// The answer to `UNREAD`, `UNREAD:<json>` with the unread count of each conversation that has unread messages.
+pub async fn unread(client: &Arc<Client>, username: &str) -> String {
+    let counts = count_unread(username, &get_rooms_of(client).await).unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to count unread messages of {}: {}", username, e);
+        Vec::new()
+    });
+    let counts: serde_json::Map<String, serde_json::Value> = counts.into_iter()
+        .map(|(conversation, count)| (conversation, count.into()))
+        .collect();
+    format!("UNREAD:{}\n", serde_json::Value::Object(counts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_message_to_db, seed_test_users};
+
+    #[test]
+    fn test_typing_throttle() {
+        let start = Instant::now();
+        let mut throttle = TypingThrottle::default();
+        assert!(throttle.allow("bob", start));
+        assert!(!throttle.allow("bob", start + Duration::from_secs(1)));
+        assert!(throttle.allow("global", start + Duration::from_secs(1)));
+        assert!(throttle.allow("bob", start + TYPING_THROTTLE));
+    }
+
+    #[test]
+    fn test_read_markers_and_unread_counts() {
+        seed_test_users(&["alice", "bob", "carol"]);
+        let first = add_message_to_db(100, "bob", "alice", "hi alice", None).unwrap();
+        let second = add_message_to_db(200, "bob", "alice", "are you there?", None).unwrap();
+        add_message_to_db(300, "carol", "global", "hello everyone", None).unwrap();
+        add_message_to_db(400, "alice", "global", "hi all", None).unwrap();
+        let carol_to_bob = add_message_to_db(500, "carol", "bob", "psst", None).unwrap();
+        let rooms = ["calc".to_string()];
+        add_message_to_db(600, "bob", "calc", "in the room", None).unwrap();
+
+        let counts = |rooms: &[String]| count_unread("alice", rooms).unwrap();
+        assert_eq!(counts(&[]), vec![("bob".to_string(), 2), ("global".to_string(), 1)]);
+        assert_eq!(counts(&rooms).len(), 3);
+
+        mark_read("alice", "bob", first).unwrap();
+        assert_eq!(counts(&[]), vec![("bob".to_string(), 1), ("global".to_string(), 1)]);
+        mark_read("alice", "bob", second).unwrap();
+        // markers never move back
+        mark_read("alice", "bob", first).unwrap();
+        assert_eq!(counts(&[]), vec![("global".to_string(), 1)]);
+
+        let message = get_message(carol_to_bob).unwrap().unwrap();
+        assert_eq!(conversation_of(&message, "bob", &[]), Some("carol".to_string()));
+        assert_eq!(conversation_of(&message, "carol", &[]), Some("bob".to_string()));
+        assert_eq!(conversation_of(&message, "alice", &rooms), None);
+    }
+}
@@ -0,0 +1,187 @@
+use axum::{
+    extract::{Json, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use crate::conn_handler::{send_to_all_connections, send_to_clients};
+use crate::events::{self, ServerEvent};
+use crate::state::{find_connection, get_active_connections, get_active_users, get_chat_rooms, ConnectionInfo};
+use crate::web_auth::error_response;
+
+const DEFAULT_KICK_REASON: &str = "Disconnected by an administrator";
+
+#[derive(Deserialize, Default)]
+pub struct KickRequest {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum NoticeTarget {
+    All,
+    User,
+    Room,
+}
+
+#[derive(Deserialize)]
+pub struct NoticeRequest {
+    target: NoticeTarget,
+    #[serde(default)]
+    name: String,
+    message: String,
+}
+
+// the chat protocol is line based, a newline in a notice or reason would end the frame early
+pub(crate) fn single_line(text: &str) -> String {
+    text.trim().replace(['\r', '\n'], " ")
+}
+
+pub(crate) fn publish_moderation(action: &str, target: String, detail: &str) {
+    events::publish(ServerEvent::Moderation { action: action.to_string(), target, detail: detail.to_string() });
+}
+
+fn kick_reason(request: Option<Json<KickRequest>>) -> String {
+    request.and_then(|Json(request)| request.reason)
+        .map(|reason| single_line(&reason))
+        .filter(|reason| !reason.is_empty())
+        .unwrap_or_else(|| DEFAULT_KICK_REASON.to_string())
+}
+
+/// Closes every connection of `username`. Returns false if the user is not online.
+pub async fn kick_user(username: &str, reason: &str) -> bool {
+    let active_users = get_active_users();
+    let active_users = active_users.read().await;
+    let Some(sessions) = active_users.get(username) else {
+        return false;
+    };
+    for client in sessions {
+        client.request_close(reason);
+        tracing::info!(target: "admin", "Kicked user {} (connection {})", username, client.id);
+    }
+    publish_moderation("kick", username.to_string(), reason);
+    true
+}
+
+/// Closes the connection with the given id, authenticated or not.
+pub async fn kick_connection(id: u64, reason: &str) -> bool {
+    let Some(client) = find_connection(id).await else {
+        return false;
+    };
+    client.request_close(reason);
+    tracing::info!(target: "admin", "Kicked connection {} from {}", id, client.peer_addr);
+    publish_moderation("kick", format!("connection {}", id), reason);
+    true
+}
+
+/// Closes every connection that has not authenticated yet, returns how many.
+pub async fn kick_unauthenticated(reason: &str) -> usize {
+    let connections = get_active_connections();
+    let connections = connections.read().await;
+    let mut count = 0;
+    for client in connections.iter().filter(|client| client.username().is_none()) {
+        client.request_close(reason);
+        count += 1;
+    }
+    if count > 0 {
+        tracing::info!(target: "admin", "Closing {} unauthenticated connections", count);
+        publish_moderation("kick_unauthenticated", format!("{} connections", count), reason);
+    }
+    count
+}
+
+/// Sends a `SERVER_NOTICE:<message>` frame, returns the number of recipients or None if the target does not exist.
+/// The global room reaches every authenticated user, like global chat messages do.
+pub async fn send_notice(target: NoticeTarget, name: &str, message: &str) -> Option<usize> {
+    let frame = format!("SERVER_NOTICE:{}\n", single_line(message));
+    let recipients = match target {
+        NoticeTarget::All => {
+            send_to_all_connections(&frame).await;
+            get_active_connections().read().await.len()
+        }
+        NoticeTarget::User => {
+            let sessions = get_active_users().read().await.get(name).cloned()?;
+            send_to_clients(&sessions, &frame);
+            sessions.len()
+        }
+        NoticeTarget::Room if name == "global" => {
+            let clients: Vec<_> = get_active_users().read().await.values().flatten().cloned().collect();
+            send_to_clients(&clients, &frame);
+            clients.len()
+        }
+        NoticeTarget::Room => {
+            let members = get_chat_rooms().read().await.get(name).cloned()?;
+            send_to_clients(&members, &frame);
+            members.len()
+        }
+    };
+    tracing::info!(target: "admin", "Sent server notice to {} connections", recipients);
+    let target = match target {
+        NoticeTarget::All => "everyone".to_string(),
+        NoticeTarget::User => format!("user {}", name),
+        NoticeTarget::Room => format!("room {}", name),
+    };
+    publish_moderation("notice", target, message);
+    Some(recipients)
+}
+
+pub async fn connections_handler() -> Json<Vec<ConnectionInfo>> {
+    let connections = get_active_connections();
+    let connections = connections.read().await;
+    Json(connections.iter().map(|client| client.info()).collect())
+}
+
+pub async fn connection_handler(Path(id): Path<u64>) -> Response {
+    match find_connection(id).await {
+        Some(client) => Json(client.info()).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "no such connection"),
+    }
+}
+
+pub async fn kick_connection_handler(Path(id): Path<u64>, request: Option<Json<KickRequest>>) -> Response {
+    if kick_connection(id, &kick_reason(request)).await {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error_response(StatusCode::NOT_FOUND, "no such connection")
+    }
+}
+
+pub async fn kick_user_handler(Path(username): Path<String>, request: Option<Json<KickRequest>>) -> Response {
+    if kick_user(&username, &kick_reason(request)).await {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error_response(StatusCode::NOT_FOUND, "user is not online")
+    }
+}
+
+pub async fn kick_unauthenticated_handler(request: Option<Json<KickRequest>>) -> Json<serde_json::Value> {
+    let count = kick_unauthenticated(&kick_reason(request)).await;
+    Json(serde_json::json!({ "disconnected": count }))
+}
+
+pub async fn notice_handler(Json(notice): Json<NoticeRequest>) -> Response {
+    if notice.message.trim().is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "message must not be empty");
+    }
+    if !matches!(notice.target, NoticeTarget::All) && notice.name.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "name is required for user and room notices");
+    }
+    match send_notice(notice.target, &notice.name, &notice.message).await {
+        Some(recipients) => Json(serde_json::json!({ "recipients": recipients })).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "no such user or room"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kick_reason() {
+        assert_eq!(kick_reason(None), DEFAULT_KICK_REASON);
+        assert_eq!(kick_reason(Some(Json(KickRequest { reason: Some("  ".to_string()) }))), DEFAULT_KICK_REASON);
+        let request = KickRequest { reason: Some("spam\nAUTH_SUCCESS".to_string()) };
+        assert_eq!(kick_reason(Some(Json(request))), "spam AUTH_SUCCESS");
+    }
+}
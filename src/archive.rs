@@ -0,0 +1,199 @@
+use axum::{
+    body::Body,
+    extract::{Json, Query},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use crate::db::{count_messages, export_messages_after, find_messages, MessageFilter, StoredMessage};
+use crate::web_auth::error_response;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+// rows fetched from the database per chunk of an export
+pub(crate) const EXPORT_BATCH_SIZE: i64 = 1000;
+
+#[derive(Deserialize, Default)]
+pub struct MessageQuery {
+    sender: Option<String>,
+    recipient: Option<String>,
+    // two comma separated usernames
+    conversation: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize, Clone, Copy, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Jsonl,
+    Csv,
+}
+
+impl ExportFormat {
+    /// The first line of an export, CSV starts with the column names.
+    pub(crate) fn header(self) -> Option<&'static str> {
+        match self {
+            ExportFormat::Jsonl => None,
+            ExportFormat::Csv => Some("id,timestamp,sender,recipient,message\r\n"),
+        }
+    }
+}
+
+// read separately from the filters, serde's flatten does not work with numbers in query strings
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: ExportFormat,
+}
+
+#[derive(Serialize)]
+pub struct MessagePage {
+    total: i64,
+    offset: i64,
+    limit: i64,
+    messages: Vec<StoredMessage>,
+}
+
+impl MessageQuery {
+    fn filter(&self) -> Result<MessageFilter, &'static str> {
+        let non_empty = |value: &Option<String>| value.as_ref().map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+        let conversation = match non_empty(&self.conversation) {
+            Some(conversation) => match conversation.split_once(',') {
+                Some((first, second)) => Some((first.trim().to_string(), second.trim().to_string())),
+                None => return Err("conversation needs two comma separated usernames"),
+            },
+            None => None,
+        };
+        Ok(MessageFilter {
+            sender: non_empty(&self.sender),
+            recipient: non_empty(&self.recipient),
+            conversation,
+            since: self.since,
+            until: self.until,
+        })
+    }
+}
+
+fn database_error(e: rusqlite::Error) -> Response {
+    tracing::error!(target: "webserver", "Failed to query messages: {}", e);
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+}
+
+/// `/api/messages`, stored messages newest first, filtered and paged.
+pub async fn messages_handler(query: Option<Query<MessageQuery>>) -> Response {
+    let Some(Query(query)) = query else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid query");
+    };
+    let filter = match query.filter() {
+        Ok(filter) => filter,
+        Err(error) => return error_response(StatusCode::BAD_REQUEST, error),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let result = count_messages(&filter)
+        .and_then(|total| Ok((total, find_messages(&filter, limit, offset)?)));
+    match result {
+        Ok((total, messages)) => Json(MessagePage { total, offset, limit, messages }).into_response(),
+        Err(e) => database_error(e),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub(crate) fn render_message(format: ExportFormat, message: &StoredMessage) -> String {
+    match format {
+        ExportFormat::Jsonl => format!("{}\n", serde_json::to_string(message).unwrap_or_default()),
+        ExportFormat::Csv => format!(
+            "{},{},{},{},{}\r\n",
+            message.id,
+            message.timestamp,
+            csv_field(&message.sender),
+            csv_field(&message.recipient),
+            csv_field(&message.message)
+        ),
+    }
+}
+
+/// `/api/messages/export`, every matching message oldest first as JSON Lines or CSV.
+/// The body is streamed in batches so exporting a large archive does not load it all into memory.
+pub async fn export_handler(export: Option<Query<ExportQuery>>, query: Option<Query<MessageQuery>>) -> Response {
+    let Some(Query(ExportQuery { format })) = export else {
+        return error_response(StatusCode::BAD_REQUEST, "format must be jsonl or csv");
+    };
+    let Some(Query(query)) = query else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid query");
+    };
+    let filter = match query.filter() {
+        Ok(filter) => filter,
+        Err(error) => return error_response(StatusCode::BAD_REQUEST, error),
+    };
+    let (content_type, file_name) = match format {
+        ExportFormat::Jsonl => ("application/x-ndjson", "messages.jsonl"),
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "messages.csv"),
+    };
+    tracing::info!(target: "webserver", "Exporting messages as {}", file_name);
+
+    let batches = stream::unfold(Some(0), move |after_id| {
+        let filter = filter.clone();
+        async move {
+            let after_id = after_id?;
+            match export_messages_after(&filter, after_id, EXPORT_BATCH_SIZE) {
+                Ok(messages) if messages.is_empty() => None,
+                Ok(messages) => {
+                    let next = (messages.len() as i64 == EXPORT_BATCH_SIZE).then(|| messages[messages.len() - 1].id);
+                    let chunk: String = messages.iter().map(|message| render_message(format, message)).collect();
+                    Some((Ok(chunk), next))
+                }
+                // ends the response early, the client sees a truncated download
+                Err(e) => {
+                    tracing::error!(target: "webserver", "Message export failed: {}", e);
+                    Some((Err(e), None))
+                }
+            }
+        }
+    });
+    let body = stream::iter(format.header().map(|row| Ok(row.to_string())));
+    let body = futures::StreamExt::chain(body, batches);
+
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)),
+        ],
+        Body::from_stream(body),
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_message() {
+        let message = StoredMessage {
+            id: 7,
+            timestamp: 100,
+            sender: "alice".to_string(),
+            recipient: "global".to_string(),
+            message: "hi, \"bob\"".to_string(),
+            parent_id: None,
+        };
+        assert_eq!(render_message(ExportFormat::Csv, &message), "7,100,alice,global,\"hi, \"\"bob\"\"\"\r\n");
+
+        let line = render_message(ExportFormat::Jsonl, &message);
+        assert!(line.ends_with('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["message"], "hi, \"bob\"");
+        assert_eq!(json["sender"], "alice");
+    }
+}
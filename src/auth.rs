@@ -1,38 +1,404 @@
+use std::collections::HashMap;
 use std::error::Error;
-use tracing::{error, info};
-use crate::config::Config;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+use crate::config::{Config, TinetConfig};
+use crate::db::UserProfile;
 
-pub async fn verify_session(config: &Config, username: &str, session_token: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
-    let trimmed_session_token = session_token.trim();
+type VerifyResult = Result<bool, Box<dyn Error + Send + Sync>>;
+// (username, sha256 of the session token), raw tokens are never kept in memory
+type SessionKey = (String, Vec<u8>);
+
+static VERIFIER: OnceLock<TinetVerifier> = OnceLock::new();
+
+/// Stops sending requests to TINET after `failure_threshold` failures in a row.
+/// Once the cooldown is over a single trial request decides whether it is back.
+#[derive(Default)]
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+impl CircuitBreaker {
+    fn allow_request(&mut self, now: Instant) -> bool {
+        match self.open_until {
+            None => true,
+            Some(open_until) if now < open_until => false,
+            Some(_) if self.trial_in_flight => false,
+            Some(_) => {
+                self.trial_in_flight = true;
+                true
+            }
+        }
+    }
+
+    fn record_success(&mut self) {
+        *self = CircuitBreaker::default();
+    }
+
+    /// Returns true if this failure opened the circuit.
+    fn record_failure(&mut self, now: Instant, threshold: u32, cooldown: Duration) -> bool {
+        self.consecutive_failures += 1;
+        self.trial_in_flight = false;
+        if self.consecutive_failures < threshold.max(1) {
+            return false;
+        }
+        self.open_until = Some(now + cooldown);
+        true
+    }
+}
+
+/// Verifies TINET sessions with one shared HTTP client, caching successful verifications.
+pub struct TinetVerifier {
+    client: reqwest::Client,
+    config: TinetConfig,
+    api_key: String,
+    // when each session was last confirmed valid by TINET
+    verified: Mutex<HashMap<SessionKey, i64>>,
+    breaker: Mutex<CircuitBreaker>,
+    // profile syncing trips its own breaker, a broken profile endpoint must not lock anyone out
+    profile_breaker: Mutex<CircuitBreaker>,
+}
+
+impl TinetVerifier {
+    pub fn new(config: &TinetConfig, api_key: &str) -> reqwest::Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .timeout(Duration::from_secs(config.request_timeout))
+            .build()?;
+        Ok(TinetVerifier {
+            client,
+            config: config.clone(),
+            api_key: api_key.to_string(),
+            verified: Mutex::new(HashMap::new()),
+            breaker: Mutex::new(CircuitBreaker::default()),
+            profile_breaker: Mutex::new(CircuitBreaker::default()),
+        })
+    }
+
+    pub async fn verify(&self, username: &str, session_token: &str) -> VerifyResult {
+        let key = (username.to_string(), Sha256::digest(session_token.as_bytes()).to_vec());
+        let now = Utc::now().timestamp();
+        if self.verified_within(&key, now, self.config.cache_ttl) {
+            debug!(target: "auth", "Session of {} verified from cache", username);
+            return Ok(true);
+        }
+
+        if !self.breaker.lock().unwrap().allow_request(Instant::now()) {
+            return self.while_unavailable(&key, now, "TINET is unavailable".into());
+        }
+
+        match self.request(username, session_token).await {
+            Ok(answer) => {
+                self.breaker.lock().unwrap().record_success();
+                match answer {
+                    Ok(true) => {
+                        self.remember(key, now);
+                        Ok(true)
+                    }
+                    Ok(false) => {
+                        self.verified.lock().unwrap().remove(&key);
+                        Ok(false)
+                    }
+                    Err(tinet_error) => {
+                        error!(target: "auth", "Error verifying session: {}", tinet_error);
+                        Err(tinet_error.into())
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(target: "auth", "Failed to reach TINET: {}", e);
+                let cooldown = Duration::from_secs(self.config.circuit_cooldown);
+                if self.breaker.lock().unwrap().record_failure(Instant::now(), self.config.failure_threshold, cooldown) {
+                    error!(target: "auth", "TINET looks down, not contacting it for {} seconds", self.config.circuit_cooldown);
+                }
+                self.while_unavailable(&key, now, e)
+            }
+        }
+    }
+
+    fn verified_within(&self, key: &SessionKey, now: i64, seconds: u64) -> bool {
+        self.verified.lock().unwrap()
+            .get(key)
+            .is_some_and(|verified_at| now - verified_at < seconds as i64)
+    }
+
+    fn remember(&self, key: SessionKey, now: i64) {
+        let retention = if self.config.grace_mode {
+            self.config.cache_ttl.max(self.config.grace_period)
+        } else {
+            self.config.cache_ttl
+        } as i64;
+        let mut verified = self.verified.lock().unwrap();
+        verified.retain(|_, verified_at| now - *verified_at < retention);
+        if retention > 0 {
+            verified.insert(key, now);
+        }
+    }
+
+    // grace mode lets users in whose exact session TINET confirmed recently
+    fn while_unavailable(&self, key: &SessionKey, now: i64, e: Box<dyn Error + Send + Sync>) -> VerifyResult {
+        if self.config.grace_mode && self.verified_within(key, now, self.config.grace_period) {
+            warn!(target: "auth", "TINET unavailable, admitting recently verified user {} in grace mode", key.0);
+            return Ok(true);
+        }
+        Err(e)
+    }
+
+    /// Fetches the TINET profile of `username`, None when profile syncing is off or the profile endpoint is considered down.
+    pub async fn fetch_profile(&self, username: &str) -> Result<Option<UserProfile>, Box<dyn Error + Send + Sync>> {
+        if self.config.profile_url.is_empty() || !self.profile_breaker.lock().unwrap().allow_request(Instant::now()) {
+            return Ok(None);
+        }
+        let response = self.client.post(&self.config.profile_url)
+            .json(&serde_json::json!({ "username": username }))
+            .header("Accept", "application/json")
+            .header("Api-Key", &self.api_key)
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) if !response.status().is_server_error() => response,
+            failed => {
+                let cooldown = Duration::from_secs(self.config.circuit_cooldown);
+                self.profile_breaker.lock().unwrap().record_failure(Instant::now(), self.config.failure_threshold, cooldown);
+                return Err(match failed {
+                    Ok(response) => format!("TINET answered with {}", response.status()).into(),
+                    Err(e) => e.into(),
+                });
+            }
+        };
+        self.profile_breaker.lock().unwrap().record_success();
+
+        let result: serde_json::Value = response.json().await?;
+        if let Some(error) = result["error"].as_str() {
+            return Err(error.to_string().into());
+        }
+        let text = |field: &str| result[field].as_str().map(str::to_string);
+        Ok(Some(UserProfile {
+            display_name: text("display_name"),
+            calculator: text("calculator"),
+            flags: result["flags"].as_array()
+                .map(|flags| flags.iter().filter_map(|flag| flag.as_str().map(str::to_string)).collect())
+                .unwrap_or_default(),
+        }))
+    }
+
+    /// The outer error means TINET could not be asked, the inner one is an error TINET answered with.
+    async fn request(&self, username: &str, session_token: &str) -> Result<Result<bool, String>, Box<dyn Error + Send + Sync>> {
+        let mut attempt = 0;
+        loop {
+            match self.request_once(username, session_token).await {
+                Err(e) if attempt < self.config.retries => {
+                    attempt += 1;
+                    debug!(target: "auth", "TINET request failed, retrying ({}/{}): {}", attempt, self.config.retries, e);
+                    tokio::time::sleep(Duration::from_millis(200 * attempt as u64)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn request_once(&self, username: &str, session_token: &str) -> Result<Result<bool, String>, Box<dyn Error + Send + Sync>> {
+        let request_json = serde_json::json!({
+            "username": username,
+            "session_token": session_token,
+        });
+        let response = self.client.post(&self.config.url)
+            .json(&request_json)
+            .header("Accept", "application/json")
+            .header("Api-Key", &self.api_key)
+            .send()
+            .await?;
+        if response.status().is_server_error() {
+            return Err(format!("TINET answered with {}", response.status()).into());
+        }
+
+        let result: serde_json::Value = response.json().await?;
+        if let Some(error) = result["error"].as_str() {
+            return Ok(Err(error.to_string()));
+        }
+        Ok(Ok(result["valid"].as_bool().unwrap_or(false)))
+    }
+}
+
+/// The verifier shared by every connection, created from the first config it is asked for with.
+pub fn verifier(config: &Config) -> &'static TinetVerifier {
+    VERIFIER.get_or_init(|| TinetVerifier::new(&config.tinet, &config.server.api_key).expect("Failed to create the TINET client"))
+}
+
+pub async fn verify_session(config: &Config, username: &str, session_token: &str) -> VerifyResult {
+    let verifier = verifier(config);
     info!(target: "auth", "Verifying session token for user: {}", username);
-    let request_json = serde_json::json!({
-        "username": username,
-        "session_token": trimmed_session_token,
-    });
-
-    let url = "https://tinet.tkbstudios.com/api/v1/user/sessions/validity-check";
-    let client = reqwest::Client::new();
-    let response = client.post(url)
-        .json(&request_json)
-        .header("Content-Type", "application/json")
-        .header("Accept", "application/json")
-        .header("Api-Key", &config.server.api_key)
-        .send()
-        .await?
-        .text()
-        .await?;
-
-    let result: serde_json::Value = serde_json::from_str(&response)?;
-
-    if let Some(error) = result["error"].as_str() {
-        error!(target: "auth", "Error verifying session: {}", error);
-        return Err(format!("AUTH_ERROR:{}", error).into());
-    }
-
-    if result["valid"].as_bool().unwrap_or(false) {
+    let is_valid = verifier.verify(username, session_token.trim()).await?;
+    if is_valid {
         info!(target: "auth", "Session verified successfully for user: {}", username);
-        return Ok(true);
+    }
+    Ok(is_valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use axum::{extract::State, http::{HeaderMap, StatusCode}, response::IntoResponse, routing::post, Json, Router};
+
+    #[derive(Default)]
+    struct MockTinet {
+        requests: AtomicUsize,
+        failing: AtomicBool,
+        slow: AtomicBool,
+        profile_failing: AtomicBool,
+    }
+
+    async fn validity_check(State(mock): State<Arc<MockTinet>>, headers: HeaderMap, Json(body): Json<serde_json::Value>) -> axum::response::Response {
+        mock.requests.fetch_add(1, Ordering::SeqCst);
+        if mock.slow.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+        }
+        if mock.failing.load(Ordering::SeqCst) {
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+        if headers.get("Api-Key").and_then(|key| key.to_str().ok()) != Some("test-key") {
+            return Json(serde_json::json!({ "error": "invalid api key" })).into_response();
+        }
+        Json(serde_json::json!({ "valid": body["session_token"] == "good" })).into_response()
+    }
+
+    async fn profile(State(mock): State<Arc<MockTinet>>, Json(body): Json<serde_json::Value>) -> axum::response::Response {
+        if mock.profile_failing.load(Ordering::SeqCst) {
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+        Json(serde_json::json!({
+            "username": body["username"],
+            "display_name": "Alice",
+            "calculator": "TI-84 Plus CE",
+            "flags": ["staff", 7],
+        })).into_response()
+    }
+
+    async fn start_mock_tinet() -> (TinetConfig, Arc<MockTinet>) {
+        let mock = Arc::new(MockTinet::default());
+        let app = Router::new()
+            .route("/validity-check", post(validity_check))
+            .route("/profile", post(profile))
+            .with_state(mock.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/validity-check", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = TinetConfig {
+            profile_url: url.replace("validity-check", "profile"),
+            url,
+            retries: 0,
+            request_timeout: 1,
+            ..TinetConfig::default()
+        };
+        (config, mock)
+    }
+
+    #[tokio::test]
+    async fn test_verify_and_cache() {
+        let (config, mock) = start_mock_tinet().await;
+        let verifier = TinetVerifier::new(&config, "test-key").unwrap();
+
+        assert!(verifier.verify("alice", "good").await.unwrap());
+        assert!(verifier.verify("alice", "good").await.unwrap());
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 1);
+
+        // failed verifications are not cached
+        assert!(!verifier.verify("alice", "bad").await.unwrap());
+        assert!(!verifier.verify("alice", "bad").await.unwrap());
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 3);
+
+        let verifier = TinetVerifier::new(&config, "wrong-key").unwrap();
+        let error = verifier.verify("alice", "good").await.unwrap_err();
+        assert_eq!(error.to_string(), "invalid api key");
     }
 
-    Ok(false)
+    #[tokio::test]
+    async fn test_circuit_breaker_and_grace_mode() {
+        let (config, mock) = start_mock_tinet().await;
+        let config = TinetConfig { cache_ttl: 0, failure_threshold: 2, grace_mode: true, ..config };
+        let verifier = TinetVerifier::new(&config, "test-key").unwrap();
+
+        assert!(verifier.verify("alice", "good").await.unwrap());
+        mock.failing.store(true, Ordering::SeqCst);
+
+        assert!(verifier.verify("bob", "good").await.is_err());
+        // the second failure opens the circuit, alice was verified recently
+        assert!(verifier.verify("alice", "good").await.unwrap());
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 3);
+
+        // TINET is not asked while the circuit is open
+        assert!(verifier.verify("bob", "good").await.is_err());
+        assert!(verifier.verify("alice", "good").await.unwrap());
+        assert!(verifier.verify("alice", "other-token").await.is_err());
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_profile() {
+        let (config, _mock) = start_mock_tinet().await;
+        let verifier = TinetVerifier::new(&config, "test-key").unwrap();
+        let profile = verifier.fetch_profile("alice").await.unwrap().unwrap();
+        assert_eq!(profile.display_name.as_deref(), Some("Alice"));
+        assert_eq!(profile.calculator.as_deref(), Some("TI-84 Plus CE"));
+        assert_eq!(profile.flags, vec!["staff".to_string()]);
+
+        let config = TinetConfig { profile_url: String::new(), ..config };
+        let verifier = TinetVerifier::new(&config, "test-key").unwrap();
+        assert!(verifier.fetch_profile("alice").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_profile_failures_leave_verification_alone() {
+        let (config, mock) = start_mock_tinet().await;
+        let config = TinetConfig { cache_ttl: 0, failure_threshold: 2, ..config };
+        let verifier = TinetVerifier::new(&config, "test-key").unwrap();
+        mock.profile_failing.store(true, Ordering::SeqCst);
+
+        assert!(verifier.fetch_profile("alice").await.is_err());
+        assert!(verifier.fetch_profile("alice").await.is_err());
+        // the profile circuit is open now
+        assert!(verifier.fetch_profile("alice").await.unwrap().is_none());
+
+        assert!(verifier.verify("alice", "good").await.unwrap());
+        assert!(!verifier.verify("alice", "bad").await.unwrap());
+        assert_eq!(mock.requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let (config, mock) = start_mock_tinet().await;
+        mock.slow.store(true, Ordering::SeqCst);
+        let verifier = TinetVerifier::new(&config, "test-key").unwrap();
+
+        let started = Instant::now();
+        assert!(verifier.verify("alice", "good").await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_circuit_breaker_trial_request() {
+        let mut breaker = CircuitBreaker::default();
+        let now = Instant::now();
+        let cooldown = Duration::from_secs(30);
+        assert!(!breaker.record_failure(now, 2, cooldown));
+        assert!(breaker.allow_request(now));
+        assert!(breaker.record_failure(now, 2, cooldown));
+        assert!(!breaker.allow_request(now + Duration::from_secs(10)));
+
+        let later = now + Duration::from_secs(31);
+        assert!(breaker.allow_request(later));
+        // only one trial at a time
+        assert!(!breaker.allow_request(later));
+        breaker.record_success();
+        assert!(breaker.allow_request(later));
+    }
 }
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+use chrono::Utc;
+use crate::db::{add_block, get_blocked, get_blockers, get_user, remove_block, StoredMessage};
+use crate::state::Client;
+use crate::validators::validate_username;
+
+/// The users that blocked `sender`.
+pub fn blockers_of(sender: &str) -> Vec<String> {
+    get_blockers(sender).unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to load who blocked {}: {}", sender, e);
+        Vec::new()
+    })
+}
+
+/// Whether `username` blocked `sender`.
+pub fn has_blocked(username: &str, sender: &str) -> bool {
+    blockers_of(sender).iter().any(|blocker| blocker == username)
+}
+
+/// `clients` without the connections of users that blocked `sender`.
+pub fn without_blockers(clients: &[Arc<Client>], sender: &str) -> Vec<Arc<Client>> {
+    let blockers = blockers_of(sender);
+    clients.iter()
+        .filter(|client| !client.username().is_some_and(|username| blockers.contains(&username)))
+        .cloned()
+        .collect()
+}
+
+/// `messages` without the ones from users `username` blocked, for history sent to them.
+pub fn visible_to(username: &str, messages: Vec<StoredMessage>) -> Vec<StoredMessage> {
+    let blocked = get_blocked(username).unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to load the users {} blocked: {}", username, e);
+        Vec::new()
+    });
+    messages.into_iter().filter(|message| !blocked.contains(&message.sender)).collect()
+}
+
+/// The answer to `?block <username>`.
+pub fn block(username: &str, target: &str) -> String {
+    if target == username {
+        return "You can't block yourself".to_string();
+    }
+    if !validate_username(target) || get_user(target).ok().flatten().is_none() {
+        return format!("No such user: {}", target);
+    }
+    match add_block(username, target, Utc::now().timestamp()) {
+        Ok(true) => {
+            tracing::info!(target: "server", "{} blocked {}", username, target);
+            format!("Blocked {}", target)
+        }
+        Ok(false) => format!("{} already is blocked", target),
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to store block of {} by {}: {}", target, username, e);
+            "Something went wrong, try again later".to_string()
+        }
+    }
+}
+
+/// The answer to `?unblock <username>`.
+pub fn unblock(username: &str, target: &str) -> String {
+    match remove_block(username, target) {
+        Ok(true) => format!("Unblocked {}", target),
+        Ok(false) => format!("{} is not blocked", target),
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to remove block of {} by {}: {}", target, username, e);
+            "Something went wrong, try again later".to_string()
+        }
+    }
+}
+
+/// The answer to `?blocked`.
+pub fn list(username: &str) -> String {
+    match get_blocked(username) {
+        Ok(blocked) if blocked.is_empty() => "You have not blocked anyone".to_string(),
+        Ok(blocked) => format!("Blocked: {}", blocked.join(", ")),
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to load the users {} blocked: {}", username, e);
+            "Something went wrong, try again later".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::seed_test_users;
+
+    #[test]
+    fn test_blocks() {
+        seed_test_users(&["alice", "bob", "carol"]);
+        assert_eq!(block("alice", "alice"), "You can't block yourself");
+        assert_eq!(block("alice", "nobody"), "No such user: nobody");
+        assert_eq!(block("alice", "bob"), "Blocked bob");
+        assert_eq!(block("alice", "bob"), "bob already is blocked");
+        block("carol", "bob");
+
+        assert!(has_blocked("alice", "bob"));
+        assert!(!has_blocked("bob", "alice"));
+        assert_eq!(blockers_of("bob").len(), 2);
+        assert_eq!(list("alice"), "Blocked: bob");
+
+        assert_eq!(unblock("alice", "bob"), "Unblocked bob");
+        assert_eq!(unblock("alice", "bob"), "bob is not blocked");
+        assert_eq!(list("alice"), "You have not blocked anyone");
+        assert_eq!(blockers_of("bob"), vec!["carol".to_string()]);
+    }
+}
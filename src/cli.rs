@@ -0,0 +1,294 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, NaiveDate};
+use clap::{Parser, Subcommand, ValueEnum};
+use rusqlite::{Connection, OpenFlags};
+use crate::archive::{render_message, ExportFormat, EXPORT_BATCH_SIZE};
+use crate::validators::validate_username;
+use crate::db::{
+    backup_db, export_messages_after, get_db_conn, init_db, list_users, purge_messages_before, restore_db,
+    schema_version, set_user_banned, set_user_permission, vacuum_db, MessageFilter, SCHEMA_VERSION,
+};
+use crate::DB_PATH;
+
+#[derive(Parser)]
+#[command(version, about = "NETCHAT server")]
+pub struct Cli {
+    /// Config file to use, defaults to config.toml and the built-in defaults if that does not exist
+    #[arg(long, env = "NETCHAT_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Validate the config, print it with secrets redacted and exit
+    #[arg(long)]
+    pub check_config: bool,
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+/// Maintenance commands, they work on the database directly and do not start the server.
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Manage users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Back up, restore and maintain the database
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Export or delete stored messages
+    #[command(subcommand)]
+    Messages(MessagesCommand),
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// List every user that ever logged in
+    List,
+    /// Change the role of a user
+    SetRole { username: String, role: Role },
+    /// Keep a user from logging in, takes effect on their next login. Works before their first login too
+    Ban {
+        username: String,
+        /// Sent to the user when they try to log in
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Lift the ban of a user
+    Unban { username: String },
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Copy the database to a file, safe while the server is running
+    Backup { path: PathBuf },
+    /// Replace the database with a backup, stop the server first
+    Restore { path: PathBuf },
+    /// Rebuild the database file to reclaim space left by deleted rows
+    Vacuum,
+    /// Bring the database schema up to date
+    Migrate,
+}
+
+#[derive(Subcommand)]
+pub enum MessagesCommand {
+    /// Write stored messages, oldest first
+    Export {
+        #[arg(long, value_enum, default_value = "jsonl")]
+        format: ExportFormat,
+        /// File to write to instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        sender: Option<String>,
+        #[arg(long)]
+        recipient: Option<String>,
+        /// Unix timestamp or YYYY-MM-DD
+        #[arg(long, value_parser = parse_time)]
+        since: Option<i64>,
+        /// Unix timestamp or YYYY-MM-DD
+        #[arg(long, value_parser = parse_time)]
+        until: Option<i64>,
+    },
+    /// Delete messages sent before a point in time
+    Purge {
+        /// Unix timestamp or YYYY-MM-DD
+        #[arg(long, value_parser = parse_time)]
+        before: i64,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+// dates are taken as midnight UTC
+fn parse_time(value: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = value.parse() {
+        return Ok(timestamp);
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())
+        .map_err(|_| format!("\"{}\" is neither a unix timestamp nor a YYYY-MM-DD date", value))
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0).map_or_else(|| timestamp.to_string(), |time| time.to_rfc3339())
+}
+
+pub fn run(command: CliCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        CliCommand::User(command) => {
+            init_db()?;
+            run_user_command(command)
+        }
+        CliCommand::Db(command) => run_db_command(command),
+        CliCommand::Messages(command) => {
+            init_db()?;
+            run_messages_command(command)
+        }
+    }
+}
+
+fn run_user_command(command: UserCommand) -> Result<(), Box<dyn Error>> {
+    let no_such_user = |username: &str| format!("No such user: {}", username).into();
+    match command {
+        UserCommand::List => {
+            let users = list_users()?;
+            println!("{:<18} {:<10} {:<8} {:>8}  BANNED", "USERNAME", "ROLE", "STATUS", "MESSAGES");
+            for user in &users {
+                let banned = match (&user.banned, &user.ban_reason) {
+                    (false, _) => String::new(),
+                    (true, Some(reason)) => format!("yes: {}", reason),
+                    (true, None) => "yes".to_string(),
+                };
+                println!("{:<18} {:<10} {:<8} {:>8}  {}", user.username, user.permission, user.status, user.messages_sent, banned);
+            }
+            println!("{} users", users.len());
+        }
+        UserCommand::SetRole { username, role } => {
+            if !set_user_permission(&username, role.as_str())? {
+                return Err(no_such_user(&username));
+            }
+            println!("{} is now {}", username, role.as_str());
+        }
+        UserCommand::Ban { username, reason } => {
+            // users that never logged in can be banned too, so the name has to be one they could log in with
+            if !validate_username(&username) {
+                return Err(format!("Invalid username: {}", username).into());
+            }
+            set_user_banned(&username, true, reason.as_deref())?;
+            println!("Banned {}", username);
+        }
+        UserCommand::Unban { username } => {
+            if !set_user_banned(&username, false, None)? {
+                return Err(no_such_user(&username));
+            }
+            println!("Unbanned {}", username);
+        }
+    }
+    Ok(())
+}
+
+fn run_db_command(command: DbCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        DbCommand::Backup { path } => {
+            init_db()?;
+            backup_db(&path)?;
+            println!("Backed up {} to {}", DB_PATH, path.display());
+        }
+        DbCommand::Restore { path } => {
+            check_backup(&path)?;
+            restore_db(&path)?;
+            // backups from older versions are brought up to the current schema
+            init_db()?;
+            println!("Restored {} from {}", DB_PATH, path.display());
+        }
+        DbCommand::Vacuum => {
+            init_db()?;
+            let size = || std::fs::metadata(DB_PATH).map(|metadata| metadata.len()).unwrap_or(0);
+            let before = size();
+            vacuum_db()?;
+            println!("Vacuumed {}: {} bytes before, {} bytes after", DB_PATH, before, size());
+        }
+        DbCommand::Migrate => {
+            let before = schema_version(&get_db_conn()?)?;
+            init_db()?;
+            if before == SCHEMA_VERSION {
+                println!("Database is up to date at version {}", SCHEMA_VERSION);
+            } else {
+                println!("Migrated database from version {} to {}", before, SCHEMA_VERSION);
+            }
+        }
+    }
+    Ok(())
+}
+
+// refuse to overwrite the live database with something that is not one of ours
+fn check_backup(path: &Path) -> Result<(), Box<dyn Error>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let integrity: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))
+        .map_err(|e| format!("{} is not a database: {}", path.display(), e))?;
+    if integrity != "ok" {
+        return Err(format!("{} is damaged: {}", path.display(), integrity).into());
+    }
+    let tables: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name IN ('users', 'messages')",
+        [],
+        |row| row.get(0),
+    )?;
+    if tables != 2 {
+        return Err(format!("{} is not a NETCHAT database", path.display()).into());
+    }
+    let version = schema_version(&conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!("{} was written by a newer version of the server (schema version {})", path.display(), version).into());
+    }
+    Ok(())
+}
+
+fn run_messages_command(command: MessagesCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        MessagesCommand::Export { format, output, sender, recipient, since, until } => {
+            let filter = MessageFilter { sender, recipient, conversation: None, since, until };
+            let mut writer: Box<dyn Write> = match &output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout().lock())),
+            };
+            let count = export_messages(&filter, format, &mut writer)?;
+            writer.flush()?;
+            // stdout may be the export itself
+            eprintln!("Exported {} messages", count);
+        }
+        MessagesCommand::Purge { before } => {
+            let deleted = purge_messages_before(before)?;
+            println!("Deleted {} messages sent before {}", deleted, format_time(before));
+        }
+    }
+    Ok(())
+}
+
+fn export_messages(filter: &MessageFilter, format: ExportFormat, writer: &mut dyn Write) -> Result<usize, Box<dyn Error>> {
+    if let Some(header) = format.header() {
+        writer.write_all(header.as_bytes())?;
+    }
+    let mut count = 0;
+    let mut after_id = 0;
+    loop {
+        let messages = export_messages_after(filter, after_id, EXPORT_BATCH_SIZE)?;
+        for message in &messages {
+            writer.write_all(render_message(format, message).as_bytes())?;
+        }
+        count += messages.len();
+        match messages.last() {
+            Some(last) if messages.len() as i64 == EXPORT_BATCH_SIZE => after_id = last.id,
+            _ => return Ok(count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1700000000"), Ok(1_700_000_000));
+        assert_eq!(parse_time("2024-01-02"), Ok(1_704_153_600));
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("2024-13-01").is_err());
+    }
+}
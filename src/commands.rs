@@ -1,10 +1,32 @@
 use std::collections::HashMap;
-use sysinfo::System;
+use crate::metrics::ProcessSampler;
+use crate::blocks;
+use crate::db::{get_mentions, get_pins, get_room, get_topic};
+use crate::rooms::{self, is_locked_out, is_room, RoomError, RoomRole, RoomSetting};
+use crate::topics::{self, can_moderate};
 use crate::state::{get_active_users};
+use crate::textutils::format_outgoing_message;
+use crate::welcome;
 use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+
+// how many mentions ?mentions lists
+const MENTIONS_LIMIT: i64 = 20;
+
+lazy_static! {
+    // ?perf gets a sampler of its own, see ProcessSampler
+    static ref PERF_SAMPLER: ProcessSampler = ProcessSampler::default();
+}
+
+/// Who runs a command, and where.
+pub struct CommandContext<'a> {
+    pub caller: &'a str,
+    // what the command was sent to: global, a room or a user
+    pub conversation: &'a str,
+}
 
 pub trait Command: Send + Sync {
-    fn execute<'a>(&'a self, args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>>;
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>>;
 }
 
 pub fn get_commands() -> HashMap<&'static str, Box<dyn Command>> {
@@ -14,6 +36,16 @@ pub fn get_commands() -> HashMap<&'static str, Box<dyn Command>> {
     commands.insert("?perf", Box::new(PerfCommand));
     commands.insert("?list", Box::new(ListCommand));
     commands.insert("?ping", Box::new(PingCommand));
+    commands.insert("?mentions", Box::new(MentionsCommand));
+    commands.insert("?topic", Box::new(TopicCommand));
+    commands.insert("?pin", Box::new(PinCommand));
+    commands.insert("?motd", Box::new(MotdCommand));
+    commands.insert("?room", Box::new(RoomCommand));
+    commands.insert("?rooms", Box::new(RoomsCommand));
+    commands.insert("?block", Box::new(BlockCommand));
+    commands.insert("?unblock", Box::new(UnblockCommand));
+    commands.insert("?blocked", Box::new(BlockedCommand));
+    commands.insert("?rules", Box::new(RulesCommand));
 
     commands
 }
@@ -21,17 +53,14 @@ pub fn get_commands() -> HashMap<&'static str, Box<dyn Command>> {
 #[derive(Clone)]
 pub struct PerfCommand;
 impl Command for PerfCommand {
-    fn execute<'a>(&'a self, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+    fn execute<'a>(&'a self, _context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
         Box::pin(async move {
-            let mut system = System::new_all();
-            system.refresh_all();
-
-            let cpu_usage = system.global_cpu_info().cpu_usage();
-            let total_memory = system.total_memory() / 1024 / 1024;
-            let used_memory = system.used_memory() / 1024 / 1024;
+            let usage = PERF_SAMPLER.sample();
+            let total_memory = usage.total_memory / 1024 / 1024;
+            let used_memory = usage.memory / 1024 / 1024;
 
             let mut response = String::new();
-            response.push_str(&format!("CPU Usage: {:.2}%", cpu_usage));
+            response.push_str(&format!("CPU Usage: {:.2}%", usage.cpu_usage));
             response.push_str(&format!(", RAM Usage: {}MB/{}MB", used_memory, total_memory));
             response.into_bytes()
         })
@@ -41,7 +70,7 @@ impl Command for PerfCommand {
 #[derive(Clone)]
 pub struct ListCommand;
 impl Command for ListCommand {
-    fn execute<'a>(&'a self, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+    fn execute<'a>(&'a self, _context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
         Box::pin(async move {
             let mut response = String::new();
             response.push_str("Users: ");
@@ -58,7 +87,7 @@ impl Command for ListCommand {
 #[derive(Clone)]
 pub struct PingCommand;
 impl Command for PingCommand {
-    fn execute<'a>(&'a self, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+    fn execute<'a>(&'a self, _context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
         Box::pin(async move {
             let mut response = String::new();
             response.push_str("Pong!");
@@ -66,3 +95,269 @@ impl Command for PingCommand {
         })
     }
 }
+
+#[derive(Clone)]
+pub struct MentionsCommand;
+impl Command for MentionsCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let mentions = match get_mentions(context.caller, MENTIONS_LIMIT) {
+                Ok(mentions) => mentions,
+                Err(e) => {
+                    tracing::error!(target: "db", "Failed to load mentions of {}: {}", context.caller, e);
+                    return b"Failed to load mentions".to_vec();
+                }
+            };
+            if mentions.is_empty() {
+                return b"No mentions".to_vec();
+            }
+
+            let mut response = format!("Mentions: {}", mentions.len());
+            for mention in &mentions {
+                response.push('\n');
+                response.push_str(&format_outgoing_message(&mention.sender, &mention.recipient, &mention.message, mention.timestamp));
+            }
+            response.into_bytes()
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct MotdCommand;
+impl Command for MotdCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move { welcome::motd_text(context.caller).await.into_bytes() })
+    }
+}
+
+#[derive(Clone)]
+pub struct RulesCommand;
+impl Command for RulesCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move { welcome::rules_text(context.caller).await.into_bytes() })
+    }
+}
+
+#[derive(Clone)]
+pub struct TopicCommand;
+impl Command for TopicCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let room = context.conversation;
+            if is_locked_out(room, context.caller) {
+                return RoomError::NotAMember.to_string().into_bytes();
+            }
+            if args.is_empty() {
+                return match get_topic(room) {
+                    Ok(Some(topic)) => format!("Topic of {}: {}", room, topic.topic).into_bytes(),
+                    Ok(None) if is_room(room) => format!("No topic set for {}", room).into_bytes(),
+                    Ok(None) => topics::RoomInfoError::NotARoom.to_string().into_bytes(),
+                    Err(e) => {
+                        tracing::error!(target: "db", "Failed to load the topic of {}: {}", room, e);
+                        topics::RoomInfoError::Database.to_string().into_bytes()
+                    }
+                };
+            }
+            if !can_moderate(room, context.caller) {
+                return b"Only moderators can change the topic".to_vec();
+            }
+            // "?topic -" removes the topic
+            let topic = args.join(" ");
+            let topic = (topic != "-").then_some(topic.as_str());
+            match topics::change_topic(room, topic, context.caller).await {
+                Ok(()) if topic.is_some() => format!("Topic of {} set", room).into_bytes(),
+                Ok(()) => format!("Topic of {} removed", room).into_bytes(),
+                Err(error) => error.to_string().into_bytes(),
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct PinCommand;
+impl Command for PinCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let room = context.conversation;
+            if is_locked_out(room, context.caller) {
+                return RoomError::NotAMember.to_string().into_bytes();
+            }
+            let message_id = match args {
+                [] => return list_pins(room).into_bytes(),
+                [id] | ["remove", id] => id.parse::<i64>(),
+                _ => return b"Usage: ?pin [remove] <message-id>".to_vec(),
+            };
+            let Ok(message_id) = message_id else {
+                return b"Usage: ?pin [remove] <message-id>".to_vec();
+            };
+            if !can_moderate(room, context.caller) {
+                return b"Only moderators can pin messages".to_vec();
+            }
+            let response = if args[0] == "remove" {
+                match topics::unpin(room, message_id, context.caller).await {
+                    Ok(true) => format!("Unpinned message {}", message_id),
+                    Ok(false) => format!("Message {} is not pinned", message_id),
+                    Err(error) => error.to_string(),
+                }
+            } else {
+                match topics::pin(room, message_id, context.caller).await {
+                    Ok(true) => format!("Pinned message {}", message_id),
+                    Ok(false) => format!("Message {} is already pinned", message_id),
+                    Err(error) => error.to_string(),
+                }
+            };
+            response.into_bytes()
+        })
+    }
+}
+
+fn list_pins(room: &str) -> String {
+    if !is_room(room) {
+        return topics::RoomInfoError::NotARoom.to_string();
+    }
+    let pins = match get_pins(room) {
+        Ok(pins) => pins,
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to load the pins of {}: {}", room, e);
+            return topics::RoomInfoError::Database.to_string();
+        }
+    };
+    if pins.is_empty() {
+        return format!("Nothing pinned in {}", room);
+    }
+    let mut response = format!("Pinned in {}: {}", room, pins.len());
+    for pin in &pins {
+        response.push_str(&format!("\n{}:{}", pin.id, format_outgoing_message(&pin.sender, &pin.recipient, &pin.message, pin.timestamp)));
+    }
+    response
+}
+
+const ROOM_USAGE: &str = "Usage: ?room [invite|kick|ban|unban|op|deop <username>] [password <password>|-] [invite-only|hidden on|off]";
+
+#[derive(Clone)]
+pub struct RoomCommand;
+impl Command for RoomCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let (room, caller) = (context.conversation, context.caller);
+            if room == "global" || !is_room(room) {
+                return RoomError::NoSuchRoom.to_string().into_bytes();
+            }
+            if is_locked_out(room, caller) {
+                return RoomError::NotAMember.to_string().into_bytes();
+            }
+            let switch = |value: &str| match value {
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => None,
+            };
+            let result = match args {
+                [] => return describe_room(room, caller).into_bytes(),
+                ["invite", user] => rooms::invite(room, caller, user).await.map(|invited| match invited {
+                    true => format!("Invited {} to {}", user, room),
+                    false => format!("{} already is in {}", user, room),
+                }),
+                ["kick", user] => rooms::remove(room, caller, user, false).await.map(|_| format!("Kicked {} from {}", user, room)),
+                ["ban", user] => rooms::remove(room, caller, user, true).await.map(|_| format!("Banned {} from {}", user, room)),
+                ["unban", user] => rooms::unban(room, caller, user).map(|unbanned| match unbanned {
+                    true => format!("Unbanned {} from {}", user, room),
+                    false => format!("{} is not banned from {}", user, room),
+                }),
+                ["op", user] => rooms::set_operator(room, caller, user, true).map(|_| format!("{} is an operator of {} now", user, room)),
+                ["deop", user] => rooms::set_operator(room, caller, user, false).map(|_| format!("{} is no operator of {} anymore", user, room)),
+                ["password", "-"] => rooms::configure(room, caller, RoomSetting::Password(None)).map(|_| format!("Removed the password of {}", room)),
+                ["password", password] => rooms::configure(room, caller, RoomSetting::Password(Some(password.to_string())))
+                    .map(|_| format!("Set the password of {}", room)),
+                ["invite-only", value] if switch(value).is_some() => rooms::configure(room, caller, RoomSetting::InviteOnly(switch(value).unwrap()))
+                    .map(|_| format!("Invite only is {} for {}", value, room)),
+                ["hidden", value] if switch(value).is_some() => rooms::configure(room, caller, RoomSetting::Hidden(switch(value).unwrap()))
+                    .map(|_| format!("Hidden is {} for {}", value, room)),
+                _ => return ROOM_USAGE.as_bytes().to_vec(),
+            };
+            result.unwrap_or_else(|error| error.to_string()).into_bytes()
+        })
+    }
+}
+
+fn describe_room(name: &str, caller: &str) -> String {
+    let Ok(Some(room)) = get_room(name) else {
+        return RoomError::NoSuchRoom.to_string();
+    };
+    let flags: Vec<&str> = [(room.invite_only, "invite only"), (room.password_hash.is_some(), "password"), (room.hidden, "hidden")]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect();
+    let mut response = format!("Room {}, owned by {}", room.name, room.owner);
+    if !flags.is_empty() {
+        response.push_str(&format!(" ({})", flags.join(", ")));
+    }
+    // who is invited or banned is for the people managing the room
+    let manager = rooms::can_manage(name, caller);
+    for (role, usernames) in rooms::access_list(name) {
+        let label = match role {
+            RoomRole::Owner => continue,
+            RoomRole::Operator => "Operators",
+            RoomRole::Member => "Members",
+            RoomRole::Invited if manager => "Invited",
+            RoomRole::Banned if manager => "Banned",
+            _ => continue,
+        };
+        response.push_str(&format!("\n{}: {}", label, usernames.join(", ")));
+    }
+    response
+}
+
+#[derive(Clone)]
+pub struct RoomsCommand;
+impl Command for RoomsCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let rooms = rooms::visible_rooms(context.caller);
+            if rooms.is_empty() {
+                return b"No rooms yet, JOIN:#<name> creates one".to_vec();
+            }
+            let rooms: Vec<String> = rooms.iter()
+                .map(|room| match (room.invite_only, room.password_hash.is_some()) {
+                    (true, _) => format!("{} (invite only)", room.name),
+                    (false, true) => format!("{} (password)", room.name),
+                    (false, false) => room.name.clone(),
+                })
+                .collect();
+            format!("Rooms: {}", rooms.join(", ")).into_bytes()
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockCommand;
+impl Command for BlockCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            match args {
+                [target] => blocks::block(context.caller, target).into_bytes(),
+                _ => b"Usage: ?block <username>".to_vec(),
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct UnblockCommand;
+impl Command for UnblockCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            match args {
+                [target] => blocks::unblock(context.caller, target).into_bytes(),
+                _ => b"Usage: ?unblock <username>".to_vec(),
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockedCommand;
+impl Command for BlockedCommand {
+    fn execute<'a>(&'a self, context: &'a CommandContext<'a>, _args: &'a [&'a str]) -> BoxFuture<'a, Vec<u8>> {
+        Box::pin(async move { blocks::list(context.caller).into_bytes() })
+    }
+}
@@ -1,25 +1,65 @@
 use std::error::Error;
-use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
 use crate::CONFIG_PATH;
+#[allow(clippy::single_component_path_imports)]
 use toml;
 
-#[derive(Debug, Deserialize, Clone)]
+/// The example config, compiled in and used when there is no config file.
+pub const DEFAULT_CONFIG: &str = include_str!("../config.toml.example");
+// every field outside [[listeners]] can be overridden with NETCHAT_<SECTION>_<FIELD>, e.g. NETCHAT_SERVER_PORT=2052
+const ENV_PREFIX: &str = "NETCHAT_";
+const REDACTED: &str = "<redacted>";
+// the api_key of the example config, which is also the built-in default
+const PLACEHOLDER_API_KEY: &str = "change me to use online-mode";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub server: ServerConfig,
     pub web: WebConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub resume: ResumeConfig,
+    #[serde(default)]
+    pub tinet: TinetConfig,
+    #[serde(default)]
+    pub connection: ConnectionConfig,
+    #[serde(default)]
+    pub welcome: WelcomeConfig,
+    // when empty the server listens on server.host and server.port only
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServerConfig {
+    #[serde(default = "default_host")]
     pub host: String,
+    #[serde(default = "default_port")]
     pub port: u16,
     pub online_mode: bool,
     pub api_key: String,
     pub protect_server: bool,
     pub server_password: String,
+    #[serde(default)]
+    pub session_policy: SessionPolicy,
+}
+
+/// What happens when a user logs in while they already have a session.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionPolicy {
+    RejectNew,
+    #[default]
+    KickOld,
+    AllowMultiple,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct WebConfig {
     pub enable: bool,
     pub host: String,
@@ -27,19 +67,409 @@ pub struct WebConfig {
     pub authentication: bool,
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub password_hash: String,
+    #[serde(default)]
+    pub api_tokens: Vec<String>,
+    #[serde(default = "default_session_timeout")]
+    pub session_timeout: u64,
+    #[serde(default = "default_max_login_attempts")]
+    pub max_login_attempts: u32,
+    #[serde(default = "default_lockout_duration")]
+    pub lockout_duration: u64,
+    #[serde(default)]
+    pub redact_events: bool,
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> u16 {
+    2052
+}
+
+fn default_session_timeout() -> u64 {
+    60 * 60
+}
+
+fn default_max_login_attempts() -> u32 {
+    5
+}
+
+fn default_lockout_duration() -> u64 {
+    5 * 60
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub filter: String,
+    pub format: LogFormat,
+    pub directory: String,
+    pub rotation: LogRotation,
+    pub max_files: usize,
+    pub redact: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            filter: String::new(),
+            format: LogFormat::Pretty,
+            directory: String::new(),
+            rotation: LogRotation::Daily,
+            max_files: 7,
+            redact: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub notice: String,
+    pub countdown: u64,
+    pub drain_timeout: u64,
+    pub restart_notice: String,
+    pub restart_countdown: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            notice: "The server is shutting down".to_string(),
+            countdown: 10,
+            drain_timeout: 10,
+            restart_notice: "The server is restarting, please reconnect".to_string(),
+            restart_countdown: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WelcomeConfig {
+    pub server_name: String,
+    // both may use {server_name}, {online} and {username}, the web UI can replace them at runtime
+    pub motd: String,
+    pub rules: String,
+}
+
+impl Default for WelcomeConfig {
+    fn default() -> Self {
+        WelcomeConfig {
+            server_name: "netchat".to_string(),
+            motd: String::new(),
+            rules: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ResumeConfig {
+    pub enable: bool,
+    pub window: u64,
+    pub replay_limit: i64,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        ResumeConfig {
+            enable: true,
+            window: 120,
+            replay_limit: 100,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TinetConfig {
+    pub url: String,
+    pub connect_timeout: u64,
+    pub request_timeout: u64,
+    pub retries: u32,
+    pub cache_ttl: u64,
+    pub failure_threshold: u32,
+    pub circuit_cooldown: u64,
+    pub grace_mode: bool,
+    pub grace_period: u64,
+    pub profile_url: String,
+    pub profile_refresh: u64,
+}
+
+impl Default for TinetConfig {
+    fn default() -> Self {
+        TinetConfig {
+            url: "https://tinet.tkbstudios.com/api/v1/user/sessions/validity-check".to_string(),
+            connect_timeout: 5,
+            request_timeout: 10,
+            retries: 1,
+            cache_ttl: 300,
+            failure_threshold: 5,
+            circuit_cooldown: 30,
+            grace_mode: false,
+            grace_period: 24 * 60 * 60,
+            profile_url: String::new(),
+            profile_refresh: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    pub heartbeat_interval: u64,
+    pub heartbeat_timeout: u64,
+    pub idle_timeout: u64,
+    pub auth_timeout: u64,
+    pub tcp_keepalive: u64,
+    pub tcp_keepalive_interval: u64,
+    pub tcp_keepalive_retries: u32,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            heartbeat_interval: 30,
+            heartbeat_timeout: 90,
+            idle_timeout: 0,
+            auth_timeout: 60,
+            tcp_keepalive: 60,
+            tcp_keepalive_interval: 10,
+            tcp_keepalive_retries: 3,
+        }
+    }
+}
+
+/// One socket the chat server accepts connections on.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ListenerConfig {
+    // matches the listener with sockets passed on by a restart or systemd, defaults to chat, chat2, chat3...
+    pub name: String,
+    // "host:port", "[ipv6]:port" or "unix:/path/to/socket"
+    pub address: String,
+    // "[::]" listens on IPv4 as well unless this is set
+    pub ipv6_only: bool,
+    pub tls: bool,
+    pub tls_cert: String,
+    pub tls_key: String,
+    // defaults to server.protect_server
+    pub require_password: Option<bool>,
+    pub protocol_version: u8,
+    // expect a PROXY protocol v1 or v2 header in front of every connection
+    pub proxy_protocol: bool,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            name: String::new(),
+            address: format!("{}:{}", default_host(), default_port()),
+            ipv6_only: false,
+            tls: false,
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            require_password: None,
+            protocol_version: 1,
+            proxy_protocol: false,
+        }
+    }
+}
+
+/// The newest chat protocol version a listener can speak.
+pub const LATEST_PROTOCOL_VERSION: u8 = 2;
+
+/// Where the effective config was read from.
+pub enum ConfigSource {
+    File(PathBuf),
+    BuiltIn,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "{}", path.display()),
+            ConfigSource::BuiltIn => write!(f, "the built-in defaults"),
+        }
+    }
 }
 
 impl Config {
-    pub fn load_config() -> Result<Self, Box<dyn Error>> {
-        let contents = std::fs::read_to_string(CONFIG_PATH)
-            .map_err(|e| format!("Failed to read config file: {}", e))?;
+    /// Reads the config file at `path`, or `config.toml` when no path is given, and applies the `NETCHAT_*` environment variables.
+    /// A missing `config.toml` falls back to the built-in defaults, a missing file that was asked for explicitly is an error.
+    pub fn load_config(path: Option<&Path>) -> Result<(Self, ConfigSource), Box<dyn Error>> {
+        let file = path.unwrap_or(Path::new(CONFIG_PATH));
+        let (contents, source) = match std::fs::read_to_string(file) {
+            Ok(contents) => (contents, ConfigSource::File(file.to_path_buf())),
+            Err(e) if path.is_none() && e.kind() == std::io::ErrorKind::NotFound => (DEFAULT_CONFIG.to_string(), ConfigSource::BuiltIn),
+            Err(e) => return Err(format!("Failed to read config file {}: {}", file.display(), e).into()),
+        };
+
+        let env = std::env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+        let config = Self::from_toml(&contents, env)?;
+        Ok((config, source))
+    }
+
+    /// Parses a config and applies the overrides among `env` to it.
+    pub fn from_toml(contents: &str, env: impl IntoIterator<Item = (String, String)>) -> Result<Self, Box<dyn Error>> {
+        let config: Config = toml::from_str(contents).map_err(|e| format!("Error deserializing config: {}", e))?;
+        let overrides: Vec<(String, String)> = env.into_iter().filter(|(name, _)| name.starts_with(ENV_PREFIX)).collect();
+        if overrides.is_empty() {
+            return Ok(config);
+        }
+
+        // serializing the parsed config fills in the defaults, so every field is there to be overridden
+        let mut table = toml::Table::try_from(&config)?;
+        for (name, value) in &overrides {
+            apply_override(&mut table, name, value)?;
+        }
+        table.try_into().map_err(|e| format!("Error applying environment overrides: {}", e).into())
+    }
+
+    /// The chat listeners with names and defaults filled in. Without a `[[listeners]]` list
+    /// that is a single one on server.host and server.port.
+    pub fn chat_listeners(&self) -> Vec<ListenerConfig> {
+        let mut listeners = self.listeners.clone();
+        if listeners.is_empty() {
+            let host = if self.server.host.contains(':') && !self.server.host.starts_with('[') {
+                format!("[{}]", self.server.host)
+            } else {
+                self.server.host.clone()
+            };
+            listeners.push(ListenerConfig { address: format!("{}:{}", host, self.server.port), ..Default::default() });
+        }
+        for (index, listener) in listeners.iter_mut().enumerate() {
+            if listener.name.is_empty() {
+                listener.name = if index == 0 { "chat".to_string() } else { format!("chat{}", index + 1) };
+            }
+            listener.require_password.get_or_insert(self.server.protect_server);
+        }
+        listeners
+    }
+
+    /// Checks for settings that parse fine but can't work together.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if self.server.online_mode && self.server.api_key.is_empty() {
+            problems.push("server.api_key is required in online mode".to_string());
+        } else if self.server.online_mode && self.server.api_key == PLACEHOLDER_API_KEY {
+            problems.push("server.api_key is still the placeholder, set your TINET API key or turn online_mode off".to_string());
+        }
+        if self.server.protect_server && self.server.server_password.is_empty() {
+            problems.push("server.server_password is required when protect_server is enabled".to_string());
+        }
+        if self.web.enable && self.web.authentication && self.web.password.is_empty() && self.web.password_hash.is_empty() {
+            problems.push("web.password or web.password_hash is required when authentication is enabled".to_string());
+        }
+        if self.web.enable && self.web.host == self.server.host && self.web.port == self.server.port {
+            problems.push("web.port must differ from server.port".to_string());
+        }
+        let connection = &self.connection;
+        if connection.heartbeat_interval > 0 && connection.heartbeat_timeout > 0 && connection.heartbeat_timeout <= connection.heartbeat_interval {
+            problems.push("connection.heartbeat_timeout must be longer than heartbeat_interval".to_string());
+        }
+        let listeners = self.chat_listeners();
+        for (index, listener) in listeners.iter().enumerate() {
+            if listener.tls && (listener.tls_cert.is_empty() || listener.tls_key.is_empty()) {
+                problems.push(format!("listener {} needs tls_cert and tls_key to use TLS", listener.name));
+            }
+            if !(1..=LATEST_PROTOCOL_VERSION).contains(&listener.protocol_version) {
+                problems.push(format!("listener {} has unknown protocol_version {}", listener.name, listener.protocol_version));
+            }
+            if listener.require_password == Some(true) && self.server.server_password.is_empty() {
+                problems.push(format!("listener {} requires a password but server.server_password is empty", listener.name));
+            }
+            if listeners[..index].iter().any(|other| other.name == listener.name) {
+                problems.push(format!("listener name {} is used twice", listener.name));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
 
-        toml::from_str(&contents).map_err(|e| format!("Error deserializing config: {}", e).into())
+    /// A copy with passwords, keys and tokens replaced, safe to print.
+    pub fn redacted(&self) -> Config {
+        let redact = |secret: &mut String| {
+            if !secret.is_empty() {
+                *secret = REDACTED.to_string();
+            }
+        };
+        let mut config = self.clone();
+        redact(&mut config.server.api_key);
+        redact(&mut config.server.server_password);
+        redact(&mut config.web.password);
+        redact(&mut config.web.password_hash);
+        config.web.api_tokens.iter_mut().for_each(redact);
+        config
     }
 }
 
+// NETCHAT_LISTEN_FDS and other variables outside the config sections are left alone,
+// an unknown field in a known section is most likely a typo and rejected
+fn apply_override(table: &mut toml::Table, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let key = name[ENV_PREFIX.len()..].to_lowercase();
+    let Some((section, field)) = key.split_once('_') else {
+        return Ok(());
+    };
+    let section_table = match table.get_mut(section) {
+        Some(toml::Value::Table(section_table)) => section_table,
+        // [[listeners]] is a list, there is no telling which listener a variable would be meant for
+        Some(_) => return Err(format!("{} can't be set from the environment", name).into()),
+        None => return Ok(()),
+    };
+    let Some(current) = section_table.get_mut(field) else {
+        return Err(format!("Unknown config field {}.{} in {}", section, field, name).into());
+    };
+
+    let invalid = |expected: &str| format!("{} must be {}, got \"{}\"", name, expected, value);
+    *current = match current {
+        toml::Value::String(_) => toml::Value::String(value.to_string()),
+        toml::Value::Integer(_) => toml::Value::Integer(value.trim().parse().map_err(|_| invalid("a number"))?),
+        toml::Value::Boolean(_) => toml::Value::Boolean(value.trim().parse().map_err(|_| invalid("true or false"))?),
+        // lists are comma separated
+        toml::Value::Array(_) => toml::Value::Array(
+            value.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| toml::Value::String(item.to_string()))
+                .collect(),
+        ),
+        _ => return Err(format!("{} can't be set from the environment", name).into()),
+    };
+    Ok(())
+}
+
+#[cfg(test)]
 pub fn get_config() -> Result<Config, Box<dyn Error>> {
-    Config::load_config()
+    let contents = std::fs::read_to_string(CONFIG_PATH)
+        .map_err(|e| format!("Failed to read config file: {}", e))?;
+    Config::from_toml(&contents, [])
 }
 
 #[cfg(test)]
@@ -47,6 +477,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_config() {
         let config = get_config().unwrap();
         assert_eq!(config.server.host, "127.0.0.1");
@@ -55,6 +486,7 @@ mod tests {
         assert_eq!(config.server.api_key, "");
         assert_eq!(config.server.protect_server, true);
         assert_eq!(config.server.server_password, "12345678");
+        assert_eq!(config.server.session_policy, SessionPolicy::AllowMultiple);
 
         assert_eq!(config.web.enable, true);
         assert_eq!(config.web.host, "127.0.0.1");
@@ -62,5 +494,86 @@ mod tests {
         assert_eq!(config.web.authentication, true);
         assert_eq!(config.web.username, "admin");
         assert_eq!(config.web.password, "admin");
+        assert_eq!(config.web.password_hash, "");
+        assert_eq!(config.web.api_tokens, vec!["test-api-token".to_string()]);
+        assert_eq!(config.web.session_timeout, 3600);
+        assert_eq!(config.web.max_login_attempts, 3);
+        assert_eq!(config.web.lockout_duration, 300);
+
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.logging.rotation, LogRotation::Daily);
+        assert!(config.logging.redact);
+
+        assert_eq!(config.shutdown.notice, "The server is shutting down");
+        assert_eq!(config.shutdown.countdown, 0);
+        assert_eq!(config.shutdown.drain_timeout, 10);
+
+        assert!(config.resume.enable);
+        assert_eq!(config.resume.window, 120);
+        assert_eq!(config.resume.replay_limit, 100);
+        assert_eq!(config.welcome.server_name, "Test server");
+        assert_eq!(config.welcome.motd.lines().count(), 2);
+        assert!(config.welcome.rules.is_empty());
+
+        assert_eq!(config.tinet.request_timeout, 10);
+        assert_eq!(config.tinet.cache_ttl, 300);
+        assert!(!config.tinet.grace_mode);
+
+        assert_eq!(config.connection.heartbeat_interval, 30);
+        assert_eq!(config.connection.auth_timeout, 60);
+        assert_eq!(config.connection.idle_timeout, 0);
+
+        let listeners = config.chat_listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].name, "chat");
+        assert_eq!(listeners[0].address, "127.0.0.1:2052");
+        assert_eq!(listeners[0].require_password, Some(true));
+        assert_eq!(listeners[0].protocol_version, 1);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env = |vars: &[(&str, &str)]| vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<Vec<_>>();
+
+        let config = Config::from_toml(DEFAULT_CONFIG, env(&[
+            ("NETCHAT_SERVER_PORT", "4000"),
+            ("NETCHAT_SERVER_ONLINE_MODE", "false"),
+            ("NETCHAT_WEB_API_TOKENS", "one, two"),
+            ("NETCHAT_TINET_GRACE_MODE", "true"),
+            ("NETCHAT_LISTEN_FDS", "chat:3"),
+            ("HOME", "/root"),
+        ])).unwrap();
+        assert_eq!(config.server.port, 4000);
+        assert!(!config.server.online_mode);
+        assert_eq!(config.web.api_tokens, vec!["one".to_string(), "two".to_string()]);
+        assert!(config.tinet.grace_mode);
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert!(config.validate().is_ok());
+
+        assert!(Config::from_toml(DEFAULT_CONFIG, env(&[("NETCHAT_SERVER_PORT", "many")])).is_err());
+        assert!(Config::from_toml(DEFAULT_CONFIG, env(&[("NETCHAT_SERVER_PROT", "4000")])).is_err());
+        let listeners = Config::from_toml(DEFAULT_CONFIG, env(&[("NETCHAT_LISTENERS_ADDRESS", "0.0.0.0:2052")]));
+        assert_eq!(listeners.unwrap_err().to_string(), "NETCHAT_LISTENERS_ADDRESS can't be set from the environment");
+
+        let invalid = Config::from_toml(DEFAULT_CONFIG, env(&[("NETCHAT_SERVER_API_KEY", "")])).unwrap();
+        assert!(invalid.validate().is_err());
+
+        let redacted = config.redacted();
+        assert_eq!(redacted.server.api_key, REDACTED);
+        assert_eq!(redacted.web.password_hash, "");
+        assert_eq!(redacted.web.api_tokens, vec![REDACTED.to_string(), REDACTED.to_string()]);
+    }
+
+    #[test]
+    fn test_check_config_rejects_placeholder_api_key() {
+        // what --check-config and startup do without a config file
+        let problems = Config::from_toml(DEFAULT_CONFIG, []).unwrap().validate().unwrap_err();
+        assert!(problems.contains("server.api_key is still the placeholder"), "{}", problems);
+
+        let env = [("NETCHAT_SERVER_API_KEY".to_string(), "real-key".to_string())];
+        assert!(Config::from_toml(DEFAULT_CONFIG, env).unwrap().validate().is_ok());
+        let env = [("NETCHAT_SERVER_ONLINE_MODE".to_string(), "false".to_string())];
+        assert!(Config::from_toml(DEFAULT_CONFIG, env).unwrap().validate().is_ok());
     }
 }
@@ -1,31 +1,86 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::Mutex;
 use tokio::time::{self, Duration};
-use std::sync::Arc;
-use tracing::{info, warn, error};
+use std::sync::{Arc, Weak};
+use tracing::{debug, info, warn, error, Instrument};
 use std::collections::HashMap;
 use chrono::Utc;
 use crate::auth::verify_session;
-use crate::config::Config;
+use crate::config::{Config, SessionPolicy};
 use crate::validators;
-use crate::commands::{Command};
-use crate::db::{add_message_to_db, add_or_update_user, get_messages, set_user_status, update_user_time_online};
-use crate::state::{get_active_connections, get_active_users};
-use crate::textutils::format_outgoing_message;
+use crate::commands::{Command, CommandContext};
+use crate::metrics;
+use crate::logging::redact;
+use crate::db::{
+    add_message_to_db, add_or_update_user, get_ban, get_last_message_id, get_messages, get_messages_since, set_user_status, update_user_time_online, StoredMessage,
+};
+use crate::resume;
+use crate::events::{self, ServerEvent};
+use crate::state::{
+    get_active_connections, get_active_users, get_rooms_of, leave_all_rooms, members_of, remove_session, sends_events, sessions_of, Client, ClientSocket,
+};
+use crate::textutils::format_message_frame;
+use crate::shutdown;
+use crate::stats;
+use crate::profiles;
+use crate::mentions;
+use crate::reactions;
+use crate::threads;
+use crate::blocks;
+use crate::rooms;
+use crate::topics;
+use crate::welcome;
+use crate::heartbeat::{Heartbeat, Liveness};
+use crate::activity::{self, TypingThrottle};
+use lazy_static::lazy_static;
+
+const REPLACED_SESSION_REASON: &str = "Logged in from another connection";
 
 pub async fn handle_connection(
-    socket: Arc<Mutex<tokio::net::TcpStream>>,
+    client: Arc<Client>,
     config: Config,
     commands: HashMap<&str, Box<dyn Command>>,
 ) {
     let mut buf = vec![0; 4 * 1024];
     let mut authenticated = false;
     let mut username = String::new();
-    let mut server_password_correct = if config.server.protect_server { false } else { true };
-    let start_time = Utc::now().timestamp();
+    let mut server_password_correct = !client.listener.require_password;
+    let mut online_session: Option<Arc<OnlineSession>> = None;
+    let mut resume_token: Option<String> = None;
+    let mut disconnect_requested = false;
+    let mut heartbeat = Heartbeat::new(std::time::Instant::now());
+    let mut typing_throttle = TypingThrottle::default();
+    events::publish(ServerEvent::Connected { conn_id: client.id, peer_addr: client.peer_addr.to_string() });
+    // protocol 1 clients do not expect anything before they speak
+    if client.listener.sends_events() {
+        let greeting = format!("PROTOCOL:{}\n", client.listener.protocol_version);
+        let _ = write_to_socket(&mut client.lock().await, greeting.as_bytes()).await;
+    }
 
     loop {
-        let mut socket_guard = socket.lock().await;
+        let mut socket_guard = client.lock().await;
+        if shutdown::is_closing() {
+            let _ = write_to_socket(&mut socket_guard, shutdown::closing_frame()).await;
+            break;
+        }
+        if let Some(reason) = client.close_reason() {
+            info!(target: "server", "Closing connection on request: {}", reason);
+            let _ = write_to_socket(&mut socket_guard, format!("KICKED:{}\n", reason).as_bytes()).await;
+            // a kicked session must not come straight back with its resume token
+            disconnect_requested = true;
+            break;
+        }
+        match heartbeat.check(&config.connection, authenticated, client.listener.protocol_version, std::time::Instant::now()) {
+            Liveness::Alive => {}
+            Liveness::Ping => {
+                let _ = write_to_socket(&mut socket_guard, b"PING\n").await;
+            }
+            Liveness::Close(reason) => {
+                info!(target: "server", "Closing connection: {}", reason);
+                let _ = write_to_socket(&mut socket_guard, format!("KICKED:{}\n", reason).as_bytes()).await;
+                break;
+            }
+        }
+
         let read_result = time::timeout(Duration::from_millis(100), socket_guard.read(&mut buf)).await;
 
         match read_result {
@@ -33,18 +88,31 @@ pub async fn handle_connection(
                 if n == 0 {
                     break;
                 }
+                metrics::BYTES_RECEIVED.inc_by(n as u64);
+                client.add_bytes_received(n);
+                heartbeat.received(std::time::Instant::now());
                 let message = String::from_utf8_lossy(&buf[..n]).to_string();
 
                 if message.trim() == "DISCONNECT" {
-                    socket_guard.write_all(b"DISCONNECTED\n").await.unwrap();
-                    socket_guard.flush().await.unwrap();
+                    write_to_socket(&mut socket_guard, b"DISCONNECTED\n").await.unwrap();
+                    disconnect_requested = true;
                     break;
                 }
+                // works before logging in too, so clients can check the connection at any time
+                if let Some(payload) = message.trim().strip_prefix("PING") {
+                    if payload.is_empty() || payload.starts_with(':') {
+                        write_to_socket(&mut socket_guard, format!("PONG{}\n", payload).as_bytes()).await.unwrap();
+                        continue;
+                    }
+                }
+                // the answer to a heartbeat, receiving it was all that mattered
+                if message.trim() == "PONG" {
+                    continue;
+                }
                 if server_password_correct {
                     if message.starts_with("AUTH:") {
                         if authenticated {
-                            socket_guard.write_all(b"ALREADY_AUTHENTICATED\n").await.unwrap();
-                            socket_guard.flush().await.unwrap();
+                            write_to_socket(&mut socket_guard, b"ALREADY_AUTHENTICATED\n").await.unwrap();
                         } else {
                             let auth_parts: Vec<&str> = message.splitn(3, ':').collect();
                             if auth_parts.len() == 3 {
@@ -52,127 +120,303 @@ pub async fn handle_connection(
                                 let session_token = auth_parts[2].trim();
 
                                 if !validators::validate_username(&username) {
-                                    socket_guard.write_all(b"INVALID_USERNAME\n").await.unwrap();
-                                    socket_guard.flush().await.unwrap();
+                                    record_auth_attempt(&client, &username, "invalid");
+                                    write_to_socket(&mut socket_guard, b"INVALID_USERNAME\n").await.unwrap();
                                     continue;
                                 }
                                 if !validators::validate_session_token(session_token) {
-                                    socket_guard.write_all(b"INVALID_SESSION_TOKEN\n").await.unwrap();
-                                    socket_guard.flush().await.unwrap();
+                                    record_auth_attempt(&client, &username, "invalid");
+                                    write_to_socket(&mut socket_guard, b"INVALID_SESSION_TOKEN\n").await.unwrap();
+                                    continue;
+                                }
+                                if let Some(reason) = ban_reason(&username) {
+                                    record_auth_attempt(&client, &username, "banned");
+                                    write_to_socket(&mut socket_guard, format!("BANNED:{}\n", reason).as_bytes()).await.unwrap();
                                     continue;
                                 }
 
-                                if config.server.online_mode {
+                                let verified = if config.server.online_mode {
                                     info!(target: "auth", "Authenticating user: {}", username);
 
                                     match verify_session(&config, &username, session_token).await {
-                                        Ok(is_valid_session) => {
-                                            if !is_valid_session {
-                                                socket_guard.write_all(b"AUTH_FAILED\n").await.unwrap();
-                                                socket_guard.flush().await.unwrap();
-                                            } else {
-                                                authenticated = true;
-                                                add_or_update_user(&username);
-                                                {
-                                                    let active_users = get_active_users();
-                                                    let mut users = active_users.write().await;
-                                                    users.insert(username.clone(), Arc::clone(&socket));
-                                                }
-                                                socket_guard.write_all(b"AUTH_SUCCESS\n").await.unwrap();
-                                                socket_guard.flush().await.unwrap();
-                                            }
+                                        Ok(true) => true,
+                                        Ok(false) => {
+                                            record_auth_attempt(&client, &username, "failed");
+                                            write_to_socket(&mut socket_guard, b"AUTH_FAILED\n").await.unwrap();
+                                            false
                                         },
                                         Err(e) => {
+                                            record_auth_attempt(&client, &username, "error");
                                             let error_message = format!("AUTH_ERROR:{}\n", e);
-                                            socket_guard.write_all(error_message.as_bytes()).await.unwrap();
-                                            socket_guard.flush().await.unwrap();
+                                            write_to_socket(&mut socket_guard, error_message.as_bytes()).await.unwrap();
+                                            false
                                         },
                                     }
                                 } else {
                                     info!(target: "auth", "Server not in online mode, marking user: {} as authenticated", username);
-                                    authenticated = true;
-                                    add_or_update_user(&username);
-                                    {
-                                        let active_users = get_active_users();
-                                        let mut users = active_users.write().await;
-                                        users.insert(username.clone(), Arc::clone(&socket));
+                                    true
+                                };
+                                if !verified {
+                                    continue;
+                                }
+                                let Some(session) = start_session(&config, &username, &client).await else {
+                                    record_auth_attempt(&client, &username, "already_logged_in");
+                                    write_to_socket(&mut socket_guard, b"ALREADY_LOGGED_IN\n").await.unwrap();
+                                    continue;
+                                };
+                                authenticated = true;
+                                record_auth_attempt(&client, &username, "success");
+                                online_session.replace(session);
+                                write_to_socket(&mut socket_guard, b"AUTH_SUCCESS\n").await.unwrap();
+                                welcome::send_welcome(&username, client.listener.protocol_version, &mut socket_guard).await;
+                                topics::send_room_info("global", client.listener.protocol_version, &mut socket_guard).await;
+                                for room in rooms::rejoin(&client, &username, &rooms::memberships(&username)).await {
+                                    if client.listener.sends_events() {
+                                        write_to_socket(&mut socket_guard, format!("JOINED:{}\n", room).as_bytes()).await.unwrap();
                                     }
-                                    socket_guard.write_all(b"AUTH_SUCCESS\n").await.unwrap();
-                                    socket_guard.flush().await.unwrap();
+                                    topics::send_room_info(&room, client.listener.protocol_version, &mut socket_guard).await;
                                 }
+                                mentions::send_missed(&username, client.listener.protocol_version, &mut socket_guard).await;
+                                spawn_profile_sync(&config, &username);
+                                resume_token = send_resume_token(&config, &username, &mut socket_guard).await;
                             } else {
                                 warn!(target: "auth", "Invalid AUTH message");
-                                socket_guard.write_all(b"AUTH_INVALID\n").await.unwrap();
-                                socket_guard.flush().await.unwrap();
+                                record_auth_attempt(&client, &username, "invalid");
+                                write_to_socket(&mut socket_guard, b"AUTH_INVALID\n").await.unwrap();
+                            }
+                        }
+                    } else if message.starts_with("RESUME:") {
+                        if authenticated {
+                            write_to_socket(&mut socket_guard, b"ALREADY_AUTHENTICATED\n").await.unwrap();
+                            continue;
+                        }
+                        let resume_parts: Vec<&str> = message.trim().splitn(3, ':').collect();
+                        if resume_parts.len() != 3 || !validators::validate_username(resume_parts[1]) {
+                            write_to_socket(&mut socket_guard, b"RESUME_INVALID\n").await.unwrap();
+                            continue;
+                        }
+                        if !config.resume.enable {
+                            write_to_socket(&mut socket_guard, b"RESUME_FAILED\n").await.unwrap();
+                            continue;
+                        }
+                        if let Some(reason) = ban_reason(resume_parts[1]) {
+                            record_auth_attempt(&client, resume_parts[1], "banned");
+                            write_to_socket(&mut socket_guard, format!("BANNED:{}\n", reason).as_bytes()).await.unwrap();
+                            continue;
+                        }
+
+                        match resume::redeem(&config.resume, resume_parts[1], resume_parts[2]) {
+                            Ok(Some(previous_session)) => {
+                                username = resume_parts[1].to_string();
+                                let Some(session) = start_session(&config, &username, &client).await else {
+                                    record_auth_attempt(&client, &username, "already_logged_in");
+                                    write_to_socket(&mut socket_guard, b"ALREADY_LOGGED_IN\n").await.unwrap();
+                                    continue;
+                                };
+                                info!(target: "auth", "Resuming session of user: {}", username);
+                                authenticated = true;
+                                record_auth_attempt(&client, &username, "resumed");
+                                online_session.replace(session);
+                                // rooms the user was kicked or banned from in the meantime stay left
+                                let resumed_rooms = rooms::rejoin(&client, &username, &previous_session.rooms).await;
+                                write_to_socket(&mut socket_guard, b"RESUME_SUCCESS\n").await.unwrap();
+                                for room in std::iter::once("global").chain(resumed_rooms.iter().map(String::as_str)) {
+                                    topics::send_room_info(room, client.listener.protocol_version, &mut socket_guard).await;
+                                }
+                                spawn_profile_sync(&config, &username);
+
+                                match replay_frames(&username, &resumed_rooms, previous_session.last_message_id, config.resume.replay_limit, client.listener.protocol_version) {
+                                    Ok((frames, last_missed)) => {
+                                        debug!(target: "tcpserver", "Replaying {} missed messages to {}", frames.len(), username);
+                                        for frame in frames {
+                                            write_to_socket(&mut socket_guard, frame.as_bytes()).await.unwrap();
+                                        }
+                                        if let Some(last) = last_missed {
+                                            client.delivered(last);
+                                        }
+                                    }
+                                    Err(e) => error!(target: "tcpserver", "Failed to fetch missed messages: {}", e),
+                                }
+                                mentions::send_missed(&username, client.listener.protocol_version, &mut socket_guard).await;
+                                resume_token = send_resume_token(&config, &username, &mut socket_guard).await;
+                            }
+                            Ok(None) => {
+                                record_auth_attempt(&client, resume_parts[1], "resume_failed");
+                                write_to_socket(&mut socket_guard, b"RESUME_FAILED\n").await.unwrap();
+                            }
+                            Err(e) => {
+                                error!(target: "auth", "Failed to redeem resume token: {}", e);
+                                write_to_socket(&mut socket_guard, b"RESUME_FAILED\n").await.unwrap();
                             }
                         }
                     } else if authenticated {
                         if message.len() > 256 {
-                            socket_guard.write_all(b"MESSAGE_TOO_LONG\n").await.unwrap();
-                            socket_guard.flush().await.unwrap();
+                            write_to_socket(&mut socket_guard, b"MESSAGE_TOO_LONG\n").await.unwrap();
                             continue;
                         }
 
                         if message.is_empty() {
-                            socket_guard.write_all(b"EMPTY_MESSAGE\n").await.unwrap();
-                            socket_guard.flush().await.unwrap();
+                            write_to_socket(&mut socket_guard, b"EMPTY_MESSAGE\n").await.unwrap();
                             continue;
                         }
 
+                        if message.starts_with("WHOIS:") {
+                            let target = message.trim_start_matches("WHOIS:").trim();
+                            write_to_socket(&mut socket_guard, profiles::whois(target).as_bytes()).await.unwrap();
+                            continue
+                        }
+
                         if message.starts_with("GET_MESSAGES:") {
                             let recipient = message.trim_start_matches("GET_MESSAGES:").trim();
-                            let messages = get_messages(recipient, 100).unwrap();
-                            info!(target: "tcpserver", "Sending messages: {:?}", messages);
-                            for message in messages {
-                                info!(target: "tcpserver", "Sending message: {}", message);
-                                socket_guard.write_all(message.as_bytes()).await.unwrap();
-                                socket_guard.flush().await.unwrap();
+                            if rooms::is_locked_out(recipient, &username) {
+                                write_to_socket(&mut socket_guard, format!("NOT_IN_ROOM:{}\n", recipient).as_bytes()).await.unwrap();
+                                continue
+                            }
+                            let messages = blocks::visible_to(&username, get_messages(recipient, 100).unwrap());
+                            debug!(target: "tcpserver", "Sending {} stored messages for {}", messages.len(), recipient);
+                            for frame in history_frames(&messages, client.listener.protocol_version) {
+                                debug!(target: "tcpserver", "Sending message: {}", redact(&frame));
+                                write_to_socket(&mut socket_guard, frame.as_bytes()).await.unwrap();
                             }
                             continue
                         }
 
+                        if message.starts_with("TYPING:") {
+                            let target = message.trim_start_matches("TYPING:").trim();
+                            if typing_throttle.allow(target, std::time::Instant::now()) {
+                                activity::forward_typing(&client, &username, target).await;
+                            }
+                            continue
+                        }
+
+                        if message.starts_with("READ:") {
+                            let message_id = message.trim_start_matches("READ:").trim();
+                            if let Some(reply) = activity::read(&client, &username, message_id).await {
+                                write_to_socket(&mut socket_guard, reply.as_bytes()).await.unwrap();
+                            }
+                            continue
+                        }
+
+                        if message.starts_with("REACT:") {
+                            let arguments = message.trim_start_matches("REACT:").trim();
+                            let reply = reactions::react(&client, &username, arguments).await;
+                            write_to_socket(&mut socket_guard, reply.as_bytes()).await.unwrap();
+                            continue
+                        }
+
+                        if message.starts_with("JOIN:") {
+                            let arguments = message.trim_start_matches("JOIN:").trim_end_matches(['\r', '\n']);
+                            let (room, password) = match arguments.split_once(':') {
+                                Some((room, password)) => (room, Some(password)),
+                                None => (arguments, None),
+                            };
+                            match rooms::join(&client, &username, room, password).await {
+                                Ok(()) => {
+                                    write_to_socket(&mut socket_guard, format!("JOINED:{}\n", room).as_bytes()).await.unwrap();
+                                    topics::send_room_info(room, client.listener.protocol_version, &mut socket_guard).await;
+                                }
+                                Err(error) => {
+                                    let reply = format!("JOIN_FAILED:{}:{}\n", room, error.code());
+                                    write_to_socket(&mut socket_guard, reply.as_bytes()).await.unwrap();
+                                }
+                            }
+                            continue
+                        }
+
+                        if message.starts_with("LEAVE:") {
+                            let room = message.trim_start_matches("LEAVE:").trim();
+                            let reply = match rooms::leave(&username, room).await {
+                                Ok(()) => format!("LEFT:{}\n", room),
+                                Err(error) => format!("LEAVE_FAILED:{}:{}\n", room, error.code()),
+                            };
+                            write_to_socket(&mut socket_guard, reply.as_bytes()).await.unwrap();
+                            continue
+                        }
+
+                        if message.trim() == "ACCEPT_RULES" {
+                            write_to_socket(&mut socket_guard, welcome::accept_rules(&username).as_bytes()).await.unwrap();
+                            continue
+                        }
+
+                        if message.starts_with("REPLY:") {
+                            if !welcome::rules_accepted(&username) {
+                                write_to_socket(&mut socket_guard, b"RULES_NOT_ACCEPTED\n").await.unwrap();
+                                continue
+                            }
+                            let Some((parent_id, text)) = message.trim_start_matches("REPLY:").split_once(':') else {
+                                write_to_socket(&mut socket_guard, b"INVALID_MESSAGE_FORMAT\n").await.unwrap();
+                                continue
+                            };
+                            match threads::reply_target(&client, &username, parent_id).await {
+                                Some(target) => match refusal(&username, &target.recipient) {
+                                    Some(reply) => write_to_socket(&mut socket_guard, reply.as_bytes()).await.unwrap(),
+                                    None => send_message(&client, &username, &target.recipient, text, Some(target.thread_id)).await,
+                                },
+                                None => write_to_socket(&mut socket_guard, format!("THREAD_NOT_FOUND:{}\n", parent_id).as_bytes()).await.unwrap(),
+                            }
+                            continue
+                        }
+
+                        if message.starts_with("GET_THREAD:") {
+                            let message_id = message.trim_start_matches("GET_THREAD:").trim();
+                            let Some(thread) = threads::thread(&client, &username, message_id).await else {
+                                write_to_socket(&mut socket_guard, format!("THREAD_NOT_FOUND:{}\n", message_id).as_bytes()).await.unwrap();
+                                continue
+                            };
+                            debug!(target: "tcpserver", "Sending thread of {} with {} messages", message_id, thread.len());
+                            for frame in history_frames(&thread, client.listener.protocol_version) {
+                                write_to_socket(&mut socket_guard, frame.as_bytes()).await.unwrap();
+                            }
+                            continue
+                        }
+
+                        if message.trim() == "UNREAD" {
+                            let reply = activity::unread(&client, &username).await;
+                            write_to_socket(&mut socket_guard, reply.as_bytes()).await.unwrap();
+                            continue
+                        }
+
                         if let Some((recipient, command_message)) = message.split_once(':') {
                             if command_message.starts_with('?') {
                                 let command_name = command_message.split_whitespace().next().unwrap();
                                 let args: Vec<&str> = command_message.split_whitespace().skip(1).collect();
                                 if let Some(command) = commands.get(command_name) {
-                                    let response = command.execute(&args).await;
-                                    socket_guard.write_all(&response).await.unwrap();
-                                    socket_guard.flush().await.unwrap();
+                                    metrics::COMMAND_INVOCATIONS.with_label_values(&[command_name]).inc();
+                                    let timer = metrics::COMMAND_DURATION.with_label_values(&[command_name]).start_timer();
+                                    let context = CommandContext { caller: &username, conversation: recipient };
+                                    let response = command.execute(&context, &args).await;
+                                    timer.observe_duration();
+                                    write_to_socket(&mut socket_guard, &response).await.unwrap();
                                     continue;
                                 }
                             }
 
-                            let timestamp = Utc::now().timestamp();
-                            let full_message = format_outgoing_message(&username, recipient, &command_message, timestamp);
-                            if recipient == "global" {
-                                broadcast_message(&full_message).await;
-                            } else {
-                                send_direct_message(recipient, &full_message).await;
+                            if !welcome::rules_accepted(&username) {
+                                write_to_socket(&mut socket_guard, b"RULES_NOT_ACCEPTED\n").await.unwrap();
+                                continue
                             }
-                            add_message_to_db(timestamp, &username, recipient, &command_message).unwrap();
+                            if let Some(reply) = refusal(&username, recipient) {
+                                write_to_socket(&mut socket_guard, reply.as_bytes()).await.unwrap();
+                                continue
+                            }
+                            send_message(&client, &username, recipient, command_message, None).await;
                         } else {
-                            socket_guard.write_all(b"INVALID_MESSAGE_FORMAT\n").await.unwrap();
-                            socket_guard.flush().await.unwrap();
+                            write_to_socket(&mut socket_guard, b"INVALID_MESSAGE_FORMAT\n").await.unwrap();
                         }
                     } else {
-                        socket_guard.write_all(b"NOT_AUTHENTICATED\n").await.unwrap();
-                        socket_guard.flush().await.unwrap();
+                        write_to_socket(&mut socket_guard, b"NOT_AUTHENTICATED\n").await.unwrap();
                     }
-                } else if !server_password_correct && config.server.protect_server {
+                } else if !server_password_correct {
                     if message.starts_with("SERVER_PASS:") {
                         let server_password = message.trim_start_matches("SERVER_PASS:").trim();
                         if server_password == config.server.server_password {
                             server_password_correct = true;
-                            socket_guard.write_all(b"SERVER_PASS_CORRECT\n").await.unwrap();
-                            socket_guard.flush().await.unwrap();
+                            write_to_socket(&mut socket_guard, b"SERVER_PASS_CORRECT\n").await.unwrap();
                         } else {
-                            socket_guard.write_all(b"SERVER_PASS_INCORRECT\n").await.unwrap();
-                            socket_guard.flush().await.unwrap();
+                            write_to_socket(&mut socket_guard, b"SERVER_PASS_INCORRECT\n").await.unwrap();
                         }
                     } else {
-                        socket_guard.write_all(b"SERVER_PASS_REQUIRED\n").await.unwrap();
-                        socket_guard.flush().await.unwrap();
+                        write_to_socket(&mut socket_guard, b"SERVER_PASS_REQUIRED\n").await.unwrap();
                     }
                 }
             }
@@ -187,68 +431,396 @@ pub async fn handle_connection(
     }
 
     info!(target: "server", "Closing connection.");
-    socket.lock().await.shutdown().await.unwrap_or_else(|_| {
+    client.lock().await.shutdown().await.unwrap_or_else(|_| {
         error!(target: "server", "Failed to shutdown socket");
     });
 
     {
         let active_connections = get_active_connections();
         let mut conns = active_connections.write().await;
-        if let Some(pos) = conns.iter().position(|x| Arc::ptr_eq(x, &socket)) {
+        if let Some(pos) = conns.iter().position(|x| Arc::ptr_eq(x, &client)) {
             conns.remove(pos);
         }
     }
+    remove_session(&username, &client).await;
+
+    if let Some(token) = resume_token {
+        let result = if disconnect_requested {
+            resume::revoke(&token)
+        } else {
+            resume::suspend(&token, &get_rooms_of(&client).await, client.last_message_id())
+        };
+        if let Err(e) = result {
+            error!(target: "server", "Failed to update resume token: {}", e);
+        }
+    }
+    leave_all_rooms(&client).await;
+    events::publish(ServerEvent::Disconnected { conn_id: client.id, username: client.username() });
+
+    drop(online_session);
+}
+
+// bans are set offline with `netchat-server user ban`, so they are looked up on every login
+fn ban_reason(username: &str) -> Option<String> {
+    get_ban(username).unwrap_or_else(|e| {
+        error!(target: "db", "Failed to look up ban of {}: {}", username, e);
+        None
+    })
+}
+
+// profile data is not needed to chat, so it is fetched without holding up the login
+fn spawn_profile_sync(config: &Config, username: &str) {
+    let config = config.clone();
+    let username = username.to_string();
+    tokio::spawn(async move {
+        profiles::sync_profile(&config, &username).await;
+    }.in_current_span());
+}
+
+fn record_auth_attempt(client: &Client, username: &str, result: &str) {
+    metrics::AUTH_ATTEMPTS.with_label_values(&[result]).inc();
+    events::publish(ServerEvent::Auth { conn_id: client.id, username: username.to_string(), result: result.to_string() });
+}
+
+/// Registers `client` as a session of `username` as the session policy says.
+/// Returns None if the user is logged in elsewhere and the policy turns the new login away.
+async fn start_session(config: &Config, username: &str, client: &Arc<Client>) -> Option<Arc<OnlineSession>> {
     {
         let active_users = get_active_users();
         let mut users = active_users.write().await;
-        users.remove(&username);
+        let sessions = users.entry(username.to_string()).or_default();
+        match config.server.session_policy {
+            SessionPolicy::RejectNew if !sessions.is_empty() => {
+                info!(target: "auth", "Rejecting login of {}, they are already logged in", username);
+                return None;
+            }
+            SessionPolicy::KickOld => {
+                for previous in sessions.drain(..) {
+                    info!(target: "auth", "Closing previous session of {} (connection {})", username, previous.id);
+                    previous.request_close(REPLACED_SESSION_REASON);
+                }
+            }
+            _ => {}
+        }
+        sessions.push(Arc::clone(client));
+        stats::user_came_online(users.len());
+    }
+    tracing::Span::current().record("username", username);
+    add_or_update_user(username);
+    // a session that gets no messages at all replays from its login on when it resumes
+    match get_last_message_id() {
+        Ok(id) => client.delivered(id),
+        Err(e) => error!(target: "db", "Failed to look up the newest message: {}", e),
     }
+    client.set_username(username);
+    Some(OnlineSession::join(username))
+}
 
-    let _ = set_user_status(&username, "offline");
-    let _ = update_user_time_online(&username, Utc::now().timestamp() - start_time);
+async fn send_resume_token(config: &Config, username: &str, socket: &mut ClientSocket<'_>) -> Option<String> {
+    if !config.resume.enable {
+        return None;
+    }
+    match resume::issue_token(&config.resume, username) {
+        Ok(token) => {
+            let _ = write_to_socket(socket, format!("RESUME_TOKEN:{}\n", token).as_bytes()).await;
+            Some(token)
+        }
+        Err(e) => {
+            error!(target: "auth", "Failed to issue resume token: {}", e);
+            None
+        }
+    }
 }
 
-async fn broadcast_message(message: &str) {
-    info!(target: "server", "Broadcasting message: {}", message);
+lazy_static! {
+    // one OnlineSession per user, shared by all of their connections
+    static ref ONLINE_SESSIONS: std::sync::Mutex<HashMap<String, Weak<OnlineSession>>> = std::sync::Mutex::new(HashMap::new());
+}
 
+/// Marks the user offline and records the time they were online once their last connection ends.
+/// Recording happens on drop so sessions still get closed when a forced shutdown drops the handlers.
+struct OnlineSession {
+    username: String,
+    start_time: i64,
+}
+
+impl OnlineSession {
+    /// The session of `username`, started now unless another connection of theirs is already online.
+    fn join(username: &str) -> Arc<Self> {
+        let mut sessions = ONLINE_SESSIONS.lock().unwrap();
+        if let Some(session) = sessions.get(username).and_then(Weak::upgrade) {
+            return session;
+        }
+        let session = Arc::new(OnlineSession {
+            username: username.to_string(),
+            start_time: Utc::now().timestamp(),
+        });
+        sessions.insert(username.to_string(), Arc::downgrade(&session));
+        session
+    }
+}
+
+impl Drop for OnlineSession {
+    fn drop(&mut self) {
+        {
+            let mut sessions = ONLINE_SESSIONS.lock().unwrap();
+            // the user may have logged in again already, that is a new session
+            if sessions.get(&self.username).is_some_and(|session| session.strong_count() == 0) {
+                sessions.remove(&self.username);
+            }
+        }
+        if let Err(e) = set_user_status(&self.username, "offline") {
+            error!(target: "server", "Failed to mark {} offline: {}", self.username, e);
+        }
+        if let Err(e) = update_user_time_online(&self.username, Utc::now().timestamp() - self.start_time) {
+            error!(target: "server", "Failed to record online time for {}: {}", self.username, e);
+        }
+    }
+}
+
+/// Writes `data` to the socket, flushes it and counts the bytes sent.
+pub(crate) async fn write_to_socket(socket: &mut ClientSocket<'_>, data: &[u8]) -> std::io::Result<()> {
+    socket.write_all(data).await?;
+    socket.flush().await?;
+    metrics::BYTES_SENT.inc_by(data.len() as u64);
+    socket.add_bytes_sent(data.len());
+    Ok(())
+}
+
+/// Sends `message` to every open connection, authenticated or not.
+pub(crate) async fn send_to_all_connections(message: &str) {
     let connections = get_active_connections();
     let connections = connections.read().await;
-    let active_users = get_active_users();
-    let active_users = active_users.read().await;
+    send_to_clients(&connections, message);
+}
 
-    for client in connections.iter() {
-        if active_users.values().any(|user| Arc::ptr_eq(user, client)) {
-            let client = client.clone();
-            let message = message.to_string();
-            info!(target: "server", "Sending to client: {:?}", client);
-            tokio::spawn(async move {
-                let mut client = client.lock().await;
-                if let Err(e) = client.write_all(message.as_bytes()).await {
-                    error!(target: "server", "Failed to send message: {}", e);
-                } else {
-                    let _ = client.flush().await;
-                    info!(target: "server", "Broadcasted message: {}", message);
-                }
-            });
+/// Queues `message` for each of `clients` without waiting on slow sockets.
+pub(crate) fn send_to_clients(clients: &[Arc<Client>], message: &str) {
+    for client in clients {
+        let client = client.clone();
+        let message = message.to_string();
+        tokio::spawn(async move {
+            let mut socket = client.lock().await;
+            if let Err(e) = write_to_socket(&mut socket, message.as_bytes()).await {
+                error!(target: "server", "Failed to send message: {}", e);
+            }
+        });
+    }
+}
+
+/// Stores a chat message and delivers it to everyone it is for.
+/// The answer to a message `username` can't send to `recipient`, None if it can be sent.
+fn refusal(username: &str, recipient: &str) -> Option<String> {
+    // a # name that is no room yet is refused too, or the messages would turn up in the room once someone creates it
+    let missing_room = validators::validate_room_name(recipient) && !rooms::is_room(recipient);
+    if missing_room || rooms::is_locked_out(recipient, username) {
+        return Some(format!("NOT_IN_ROOM:{}\n", recipient));
+    }
+    // the reply does not say why, nobody should learn they are blocked
+    if blocks::has_blocked(recipient, username) {
+        return Some(format!("MESSAGE_REJECTED:{}\n", recipient));
+    }
+    None
+}
+
+async fn send_message(client: &Arc<Client>, username: &str, recipient: &str, text: &str, parent_id: Option<i64>) {
+    let timestamp = Utc::now().timestamp();
+    let id = add_message_to_db(timestamp, username, recipient, text, parent_id).unwrap();
+    let stored_message = StoredMessage {
+        id,
+        timestamp,
+        sender: username.to_string(),
+        recipient: recipient.to_string(),
+        message: text.trim_end_matches(['\r', '\n']).to_string(),
+        parent_id,
+    };
+    if recipient == "global" {
+        broadcast_message(&stored_message).await;
+        mentions::notify(&stored_message).await;
+    } else if rooms::is_room(recipient) {
+        deliver_message(&blocks::without_blockers(&members_of(recipient).await, username), &stored_message);
+    } else {
+        send_direct_message(recipient, &stored_message).await;
+        // the sender's other devices follow the conversation too
+        if recipient != username {
+            let mut other_sessions = sessions_of(username).await;
+            other_sessions.retain(|session| !Arc::ptr_eq(session, client));
+            deliver_message(&other_sessions, &stored_message);
         }
     }
+    metrics::MESSAGES_SENT.with_label_values(&[metrics::recipient_type(recipient)]).inc();
+    events::publish(ServerEvent::Message {
+        conn_id: client.id,
+        sender: username.to_string(),
+        recipient: recipient.to_string(),
+        message: text.trim_end().to_string(),
+        timestamp,
+    });
 }
 
-async fn send_direct_message(target: &str, message: &str) {
-    let active_users = get_active_users();
-    let active_users = active_users.read().await;
-    if let Some(client) = active_users.get(target) {
+/// The frames replaying what a resumed session missed, and the id of the last missed message.
+/// Messages from users it blocked are left out, but still count as delivered.
+fn replay_frames(username: &str, rooms: &[String], after_id: i64, limit: i64, protocol_version: u8) -> rusqlite::Result<(Vec<String>, Option<i64>)> {
+    let missed_messages = get_messages_since(username, rooms, after_id, limit)?;
+    let last_missed = missed_messages.last().map(|message| message.id);
+    let frames = history_frames(&blocks::visible_to(username, missed_messages), protocol_version);
+    Ok((frames, last_missed))
+}
+
+/// Stored messages as frames for a client, protocol 2 clients also get the reactions after each message.
+fn history_frames(messages: &[StoredMessage], protocol_version: u8) -> Vec<String> {
+    let summaries = if sends_events(protocol_version) { reactions::summaries(messages) } else { HashMap::new() };
+    let mut frames = Vec::new();
+    for message in messages {
+        frames.push(format_message_frame(message, protocol_version));
+        if let Some(summary) = summaries.get(&message.id) {
+            frames.push(summary.clone());
+        }
+    }
+    frames
+}
+
+/// Sends a chat message to each of `clients` in the format their protocol version expects.
+fn deliver_message(clients: &[Arc<Client>], message: &StoredMessage) {
+    for client in clients {
         let client = client.clone();
-        let message = message.to_string();
-        info!(target: "server", "Sending direct message: {}", message);
+        let frame = format_message_frame(message, client.listener.protocol_version);
+        let id = message.id;
         tokio::spawn(async move {
-            let mut client = client.lock().await;
-            if let Err(e) = client.write_all(message.as_bytes()).await {
-                error!(target: "server", "Failed to send direct message: {}", e);
+            let mut socket = client.lock().await;
+            if let Err(e) = write_to_socket(&mut socket, frame.as_bytes()).await {
+                error!(target: "server", "Failed to send message: {}", e);
             } else {
-                let _ = client.flush().await;
-                info!(target: "server", "Sent direct message: {}", message);
+                client.delivered(id);
+                debug!(target: "server", "Sent message: {}", redact(&frame));
             }
-        });
+        }.in_current_span());
+    }
+}
+
+async fn broadcast_message(message: &StoredMessage) {
+    debug!(target: "server", "Broadcasting message: {}", redact(&message.message));
+
+    let connections = get_active_connections();
+    let connections = connections.read().await;
+    let active_users = get_active_users();
+    let active_users = active_users.read().await;
+
+    let recipients: Vec<_> = connections.iter()
+        .filter(|client| active_users.values().flatten().any(|user| Arc::ptr_eq(user, client)))
+        .cloned()
+        .collect();
+    // users that blocked the sender don't see their messages
+    deliver_message(&blocks::without_blockers(&recipients, &message.sender), message);
+}
+
+async fn send_direct_message(target: &str, message: &StoredMessage) {
+    let active_users = get_active_users();
+    let active_users = active_users.read().await;
+    let Some(sessions) = active_users.get(target) else {
+        return;
+    };
+    if blocks::has_blocked(target, &message.sender) {
+        return;
+    }
+    debug!(target: "server", "Sending direct message: {}", redact(&message.message));
+    deliver_message(sessions, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_config;
+    use crate::db::{add_block, init_db, seed_test_room, seed_test_users};
+    use crate::state::{ListenerOptions, PeerAddr, TEST_STATE_LOCK};
+
+    fn test_client(id: u64) -> Arc<Client> {
+        let listener = Arc::new(ListenerOptions { name: "chat".to_string(), tls: false, require_password: false, protocol_version: 2 });
+        let (stream, _) = tokio::io::duplex(64);
+        Arc::new(Client::new(id, PeerAddr::Unix, listener, Box::new(stream)))
+    }
+
+    fn config_with(session_policy: SessionPolicy) -> Config {
+        let mut config = get_config().unwrap();
+        config.server.session_policy = session_policy;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_session_policies() {
+        init_db().unwrap();
+        let _state = TEST_STATE_LOCK.lock().await;
+        let (first, second) = (test_client(1), test_client(2));
+
+        let config = config_with(SessionPolicy::RejectNew);
+        let session = start_session(&config, "policyuser", &first).await;
+        assert!(session.is_some());
+        assert!(start_session(&config, "policyuser", &second).await.is_none());
+        assert_eq!(sessions_of("policyuser").await.len(), 1);
+        assert!(Arc::ptr_eq(&sessions_of("policyuser").await[0], &first));
+
+        let config = config_with(SessionPolicy::KickOld);
+        let _replacement = start_session(&config, "policyuser", &second).await.unwrap();
+        let sessions = sessions_of("policyuser").await;
+        assert_eq!(sessions.len(), 1);
+        assert!(Arc::ptr_eq(&sessions[0], &second));
+        assert_eq!(first.close_reason().as_deref(), Some(REPLACED_SESSION_REASON));
+        assert_eq!(second.close_reason(), None);
+        remove_session("policyuser", &first).await;
+        remove_session("policyuser", &second).await;
+        assert!(get_active_users().read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_sessions_stay_online_independently() {
+        init_db().unwrap();
+        let _state = TEST_STATE_LOCK.lock().await;
+        let (first, second) = (test_client(3), test_client(4));
+
+        let config = config_with(SessionPolicy::AllowMultiple);
+        let first_session = start_session(&config, "multiuser", &first).await.unwrap();
+        let second_session = start_session(&config, "multiuser", &second).await.unwrap();
+        // both connections share one online session
+        assert!(Arc::ptr_eq(&first_session, &second_session));
+        assert_eq!(sessions_of("multiuser").await.len(), 2);
+        assert_eq!(first.close_reason(), None);
+
+        remove_session("multiuser", &first).await;
+        let sessions = sessions_of("multiuser").await;
+        assert_eq!(sessions.len(), 1);
+        assert!(Arc::ptr_eq(&sessions[0], &second));
+        assert!(get_active_users().read().await.contains_key("multiuser"));
+
+        remove_session("multiuser", &second).await;
+        assert!(!get_active_users().read().await.contains_key("multiuser"));
+    }
+
+    #[test]
+    fn test_replay_leaves_out_blocked_senders() {
+        seed_test_users(&["replayuser", "friend", "pest"]);
+        add_block("replayuser", "pest", 100).unwrap();
+        let seen = add_message_to_db(100, "friend", "global", "before the disconnect", None).unwrap();
+        add_message_to_db(110, "friend", "replayuser", "still there?", None).unwrap();
+        let last = add_message_to_db(120, "pest", "replayuser", "hello??", None).unwrap();
+
+        let (frames, last_missed) = replay_frames("replayuser", &[], seen, 100, 1).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].contains("still there?"));
+        // the blocked message is skipped, not replayed on the next resume
+        assert_eq!(last_missed, Some(last));
+    }
+
+    #[test]
+    fn test_messages_to_rooms_that_do_not_exist_are_refused() {
+        seed_test_users(&["planter", "founder", "pest"]);
+        assert_eq!(refusal("planter", "#future").as_deref(), Some("NOT_IN_ROOM:#future\n"));
+        assert_eq!(refusal("planter", "founder"), None);
+
+        seed_test_room("#future", "founder");
+        assert_eq!(refusal("founder", "#future"), None);
+        assert_eq!(refusal("planter", "#future").as_deref(), Some("NOT_IN_ROOM:#future\n"));
+
+        add_block("founder", "pest", 100).unwrap();
+        assert_eq!(refusal("pest", "founder").as_deref(), Some("MESSAGE_REJECTED:founder\n"));
     }
 }
@@ -1,13 +1,36 @@
 use chrono::Utc;
-use rusqlite::{Connection, params, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use rusqlite::{Connection, DatabaseName, OptionalExtension, params, params_from_iter, Result};
+use rusqlite::types::Value;
+use serde::Serialize;
 use crate::DB_PATH;
-use crate::textutils::format_outgoing_message;
-use crate::validators::validate_username;
+use crate::metrics::db_timer;
+use crate::validators::{validate_room_name, validate_username};
 
+#[cfg(not(test))]
 pub fn get_db_conn() -> Result<Connection> {
     Connection::open(DB_PATH)
 }
 
+// every test runs on its own thread, give each one a private in-memory database
+// that lives as long as the thread does
+#[cfg(test)]
+pub fn get_db_conn() -> Result<Connection> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT_TEST_DB: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static TEST_DB_URI: String = format!(
+            "file:{}-{}?mode=memory&cache=shared",
+            DB_PATH,
+            NEXT_TEST_DB.fetch_add(1, Ordering::SeqCst)
+        );
+        static TEST_DB_KEEPALIVE: Connection = Connection::open(TEST_DB_URI.with(String::clone)).unwrap();
+    }
+    TEST_DB_KEEPALIVE.with(|_| ());
+    Connection::open(TEST_DB_URI.with(String::clone))
+}
+
 pub fn init_db() -> Result<Connection> {
     let conn = get_db_conn()?;
     conn.execute("
@@ -36,10 +59,151 @@ pub fn init_db() -> Result<Connection> {
             FOREIGN KEY(username) REFERENCES users(username) ON DELETE CASCADE
         )", [],
     )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS resume_tokens (
+            token_hash TEXT PRIMARY KEY,
+            username TEXT,
+            issued_at INTEGER,
+            disconnected_at INTEGER,
+            rooms TEXT,
+            last_message_id INTEGER
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS read_markers (
+            username TEXT,
+            conversation TEXT,
+            last_read_id INTEGER NOT NULL,
+            PRIMARY KEY (username, conversation)
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS mentions (
+            message_id INTEGER,
+            username TEXT,
+            notified INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (message_id, username)
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS reactions (
+            message_id INTEGER,
+            username TEXT,
+            reaction TEXT,
+            created_at INTEGER,
+            PRIMARY KEY (message_id, username, reaction)
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS topics (
+            conversation TEXT PRIMARY KEY,
+            topic TEXT NOT NULL,
+            set_by TEXT,
+            set_at INTEGER
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS pins (
+            conversation TEXT,
+            message_id INTEGER,
+            pinned_by TEXT,
+            pinned_at INTEGER,
+            PRIMARY KEY (conversation, message_id)
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS rooms (
+            name TEXT PRIMARY KEY,
+            owner TEXT NOT NULL,
+            invite_only INTEGER NOT NULL DEFAULT 0,
+            hidden INTEGER NOT NULL DEFAULT 0,
+            password_hash TEXT,
+            created_at INTEGER
+        )", [],
+    )?;
+    // one role per user and room: owner, operator, member, invited or banned
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS room_access (
+            room TEXT,
+            username TEXT,
+            role TEXT NOT NULL,
+            changed_by TEXT,
+            changed_at INTEGER,
+            PRIMARY KEY (room, username)
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS blocks (
+            username TEXT,
+            blocked TEXT,
+            blocked_at INTEGER,
+            PRIMARY KEY (username, blocked)
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS rules_acknowledgements (
+            username TEXT PRIMARY KEY,
+            rules_hash TEXT NOT NULL,
+            acknowledged_at INTEGER
+        )", [],
+    )?;
+    conn.execute("
+        CREATE TABLE IF NOT EXISTS message_stats (
+            hour INTEGER PRIMARY KEY,
+            count INTEGER NOT NULL
+        )", [],
+    )?;
+    // databases from before message_stats existed get their history counted once
+    conn.execute("
+        INSERT INTO message_stats (hour, count)
+        SELECT (CAST(timestamp AS INTEGER) / 3600) * 3600, COUNT(*) FROM messages
+        WHERE NOT EXISTS (SELECT 1 FROM message_stats)
+        GROUP BY 1
+        ", [],
+    )?;
+    conn.execute("
+        INSERT OR IGNORE INTO server_data (key, value) VALUES ('messages_sent', '0'), ('total_time_online', '0'), ('peak_concurrent_users', '0')
+        ", [],
+    )?;
+    migrate(&conn)?;
     Ok(conn)
 }
 
+// schema changes to tables that already exist, each entry moves the database up one
+// `user_version`. Only ever append to this list, released databases depend on the order
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE users ADD COLUMN display_name TEXT;
+     ALTER TABLE users ADD COLUMN calculator TEXT;
+     ALTER TABLE users ADD COLUMN flags TEXT;
+     ALTER TABLE users ADD COLUMN profile_synced_at INTEGER;",
+    "ALTER TABLE users ADD COLUMN banned INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE users ADD COLUMN ban_reason TEXT;",
+    "ALTER TABLE messages ADD COLUMN parent_id INTEGER;
+     CREATE INDEX IF NOT EXISTS messages_parent_id ON messages (parent_id);",
+];
+
+/// The `user_version` of a database with every migration applied.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Applies the migrations the database has not seen yet, returns how many ran.
+pub fn migrate(conn: &Connection) -> Result<usize> {
+    let version = schema_version(conn)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = conn.unchecked_transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        tracing::info!(target: "db", "Migrated database to version {}", index + 1);
+    }
+    Ok(SCHEMA_VERSION.saturating_sub(version))
+}
+
 pub fn add_or_update_user(username: &str) {
+    let _timer = db_timer("add_or_update_user");
     let conn = get_db_conn().unwrap();
     let mut stmt = conn.prepare("SELECT username FROM users WHERE username = ?1").unwrap();
     let user_exists = stmt.exists(params![username]).unwrap();
@@ -57,15 +221,24 @@ pub fn add_or_update_user(username: &str) {
     }
 }
 
-pub fn set_user_status(username: &str, status: &str) {
-    let conn = get_db_conn().unwrap();
+pub fn set_user_status(username: &str, status: &str) -> Result<()> {
+    let _timer = db_timer("set_user_status");
+    let conn = get_db_conn()?;
     conn.execute(
         "UPDATE users SET status = ?1 WHERE username = ?2",
         params![status, username],
-    ).unwrap();
+    )?;
+    Ok(())
+}
+
+pub fn mark_all_users_offline() -> Result<usize> {
+    let _timer = db_timer("mark_all_users_offline");
+    let conn = get_db_conn()?;
+    conn.execute("UPDATE users SET status = 'offline' WHERE status = 'online'", [])
 }
 
 pub fn increment_user_sent_messages(username: &str) -> Result<()> {
+    let _timer = db_timer("increment_user_sent_messages");
     let conn = get_db_conn()?;
 
     conn.execute(
@@ -82,6 +255,7 @@ pub fn increment_user_sent_messages(username: &str) -> Result<()> {
 }
 
 pub fn update_user_time_online(username: &str, time_online: i64) -> Result<()> {
+    let _timer = db_timer("update_user_time_online");
     let conn = get_db_conn()?;
 
     conn.execute(
@@ -97,43 +271,860 @@ pub fn update_user_time_online(username: &str, time_online: i64) -> Result<()> {
     Ok(())
 }
 
+#[allow(dead_code)]
 pub fn update_user_data(username: &str, key: &str, value: &str) {
     let conn = get_db_conn().unwrap();
     let sql = format!("UPDATE users SET {} = ?1 WHERE username = ?2", key);
     conn.execute(&sql, params![value, username]).unwrap();
 }
 
-pub fn add_message_to_db(timestamp: i64, username: &str, recipient: &str, message: &str) -> Result<()> {
+/// Stores a message, a reply in the thread of `parent_id` if it has one, and returns its id.
+pub fn add_message_to_db(timestamp: i64, username: &str, recipient: &str, message: &str, parent_id: Option<i64>) -> Result<i64> {
+    let _timer = db_timer("add_message");
     let conn = get_db_conn()?;
     conn.execute(
-        "INSERT INTO messages (timestamp, username, recipient, message) VALUES (?1, ?2, ?3, ?4)",
-        params![timestamp, username, recipient, message],
+        "INSERT INTO messages (timestamp, username, recipient, message, parent_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![timestamp, username, recipient, message, parent_id],
     )?;
-    increment_user_sent_messages(&username).unwrap();
-    Ok(())
+    let id = conn.last_insert_rowid();
+    conn.execute(
+        "INSERT INTO message_stats (hour, count) VALUES ((?1 / 3600) * 3600, 1) ON CONFLICT(hour) DO UPDATE SET count = count + 1",
+        params![timestamp],
+    )?;
+    increment_user_sent_messages(username).unwrap();
+    Ok(id)
 }
 
-pub fn get_messages(recipient: &str, limit: i64) -> Result<Vec<String>> {
-    if !validate_username(recipient) {
+pub fn get_messages(recipient: &str, limit: i64) -> Result<Vec<StoredMessage>> {
+    if !validate_username(recipient) && !validate_room_name(recipient) {
         return Ok(vec![]);
     }
+    let _timer = db_timer("get_messages");
     let conn = get_db_conn()?;
-    let mut stmt = conn.prepare("SELECT timestamp, username, recipient, message FROM messages WHERE recipient = ?1 ORDER BY id DESC LIMIT ?2")?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM messages WHERE recipient = ?1 ORDER BY id DESC LIMIT ?2", MESSAGE_COLUMNS))?;
 
     let messages = stmt.query_map(params![recipient, limit], |row| {
-        let timestamp: i64 = row.get(0)?;
-        let username: String = row.get(1)?;
-        let recipient: String = row.get(2)?;
-        let message: String = row.get(3)?;
-        tracing::info!(target: "db", "Got message from {} to {}: {}", username, recipient, message);
-        Ok(format_outgoing_message(&username, &recipient, &message, timestamp))
+        let message = stored_message_from_row(row)?;
+        tracing::debug!(target: "db", "Got message from {} to {}: {}", message.sender, message.recipient, crate::logging::redact(&message.message));
+        Ok(message)
     })?;
 
-    let messages: Vec<String> = messages.filter_map(Result::ok).collect();
-    tracing::info!(target: "db", "Total messages fetched: {}", messages.len());
+    let messages: Vec<StoredMessage> = messages.filter_map(Result::ok).collect();
+    tracing::debug!(target: "db", "Total messages fetched: {}", messages.len());
     Ok(messages)
 }
 
+/// Profile fields pulled from TINET.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct UserProfile {
+    pub display_name: Option<String>,
+    pub calculator: Option<String>,
+    pub flags: Vec<String>,
+}
+
+/// Everything stored about a user.
+#[derive(Debug, Serialize)]
+pub struct UserRecord {
+    pub username: String,
+    pub status: String,
+    pub last_online: String,
+    pub messages_sent: i64,
+    pub total_time_online: String,
+    pub permission: String,
+    #[serde(flatten)]
+    pub profile: UserProfile,
+    pub profile_synced_at: Option<i64>,
+    pub banned: bool,
+    pub ban_reason: Option<String>,
+}
+
+const USER_COLUMNS: &str = "username, status, last_online, messages_sent, total_time_online, permission, display_name, calculator, flags, profile_synced_at, banned, ban_reason";
+
+fn user_from_row(row: &rusqlite::Row) -> Result<UserRecord> {
+    let flags: Option<String> = row.get(8)?;
+    Ok(UserRecord {
+        username: row.get(0)?,
+        status: row.get(1)?,
+        last_online: row.get(2)?,
+        messages_sent: row.get(3)?,
+        total_time_online: row.get(4)?,
+        permission: row.get(5)?,
+        profile: UserProfile {
+            display_name: row.get(6)?,
+            calculator: row.get(7)?,
+            flags: flags.and_then(|flags| serde_json::from_str(&flags).ok()).unwrap_or_default(),
+        },
+        profile_synced_at: row.get(9)?,
+        banned: row.get(10)?,
+        ban_reason: row.get(11)?,
+    })
+}
+
+pub fn update_user_profile(username: &str, profile: &UserProfile, synced_at: i64) -> Result<()> {
+    let _timer = db_timer("update_user_profile");
+    let conn = get_db_conn()?;
+    let flags = serde_json::to_string(&profile.flags).unwrap_or_else(|_| "[]".to_string());
+    conn.execute(
+        "UPDATE users SET display_name = ?1, calculator = ?2, flags = ?3, profile_synced_at = ?4 WHERE username = ?5",
+        params![profile.display_name, profile.calculator, flags, synced_at, username],
+    )?;
+    Ok(())
+}
+
+pub fn get_user(username: &str) -> Result<Option<UserRecord>> {
+    let _timer = db_timer("get_user");
+    let conn = get_db_conn()?;
+    conn.query_row(&format!("SELECT {} FROM users WHERE username = ?1", USER_COLUMNS), params![username], user_from_row)
+        .optional()
+}
+
+pub fn list_users() -> Result<Vec<UserRecord>> {
+    let _timer = db_timer("list_users");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM users ORDER BY username", USER_COLUMNS))?;
+    let users = stmt.query_map([], user_from_row)?;
+    users.collect()
+}
+
+/// Returns false if there is no such user.
+pub fn set_user_permission(username: &str, permission: &str) -> Result<bool> {
+    let _timer = db_timer("set_user_permission");
+    let conn = get_db_conn()?;
+    let updated = conn.execute("UPDATE users SET permission = ?1 WHERE username = ?2", params![permission, username])?;
+    Ok(updated > 0)
+}
+
+/// Bans or unbans a user, returns false if there is no such user to unban.
+/// Users that never logged in are added when they are banned, so the ban is there before their first login.
+pub fn set_user_banned(username: &str, banned: bool, reason: Option<&str>) -> Result<bool> {
+    let _timer = db_timer("set_user_banned");
+    let conn = get_db_conn()?;
+    let updated = if banned {
+        conn.execute(
+            "INSERT INTO users (username, status, last_online, messages_sent, total_time_online, permission, banned, ban_reason)
+             VALUES (?1, 'offline', '0', 0, '0', 'user', 1, ?2)
+             ON CONFLICT(username) DO UPDATE SET banned = 1, ban_reason = excluded.ban_reason",
+            params![username, reason],
+        )?
+    } else {
+        conn.execute("UPDATE users SET banned = 0, ban_reason = NULL WHERE username = ?1", params![username])?
+    };
+    Ok(updated > 0)
+}
+
+/// The ban reason of a banned user, an empty string if none was given. None if the user is not banned.
+pub fn get_ban(username: &str) -> Result<Option<String>> {
+    let _timer = db_timer("get_ban");
+    let conn = get_db_conn()?;
+    let ban: Option<Option<String>> = conn.query_row(
+        "SELECT ban_reason FROM users WHERE username = ?1 AND banned = 1",
+        params![username],
+        |row| row.get(0),
+    ).optional()?;
+    Ok(ban.map(Option::unwrap_or_default))
+}
+
+/// Raises the recorded peak of concurrently online users if `online_users` beats it.
+pub fn record_online_users(online_users: usize, timestamp: i64) -> Result<()> {
+    let _timer = db_timer("record_online_users");
+    let conn = get_db_conn()?;
+    let raised = conn.execute(
+        "UPDATE server_data SET value = ?1 WHERE key = 'peak_concurrent_users' AND CAST(value AS INTEGER) < ?1",
+        params![online_users as i64],
+    )?;
+    if raised > 0 {
+        conn.execute(
+            "INSERT INTO server_data (key, value) VALUES ('peak_concurrent_users_at', ?1) ON CONFLICT(key) DO UPDATE SET value = ?1",
+            params![timestamp.to_string()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Message counts per `bucket_seconds` long bucket starting at `since`, only buckets that have messages.
+/// Buckets are built from the hourly counts, so `bucket_seconds` has to be a multiple of an hour.
+pub fn get_message_counts(bucket_seconds: i64, since: i64) -> Result<Vec<(i64, i64)>> {
+    let _timer = db_timer("get_message_counts");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare(
+        "SELECT (hour / ?1) * ?1, SUM(count) FROM message_stats WHERE hour >= ?2 GROUP BY 1 ORDER BY 1"
+    )?;
+    let counts = stmt.query_map(params![bucket_seconds, since], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    counts.collect()
+}
+
+pub fn get_top_posters(limit: i64) -> Result<Vec<(String, i64)>> {
+    let _timer = db_timer("get_top_posters");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare(
+        "SELECT username, messages_sent FROM users WHERE messages_sent > 0 ORDER BY messages_sent DESC, username LIMIT ?1"
+    )?;
+    let posters = stmt.query_map(params![limit], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    posters.collect()
+}
+
+pub fn count_registered_users() -> Result<i64> {
+    let _timer = db_timer("count_registered_users");
+    let conn = get_db_conn()?;
+    conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))
+}
+
+/// A stored message, as chat clients, the web UI and exports see it.
+#[derive(Debug, Serialize)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub timestamp: i64,
+    pub sender: String,
+    pub recipient: String,
+    pub message: String,
+    // the thread this message is a reply in
+    pub parent_id: Option<i64>,
+}
+
+/// Narrows down stored messages, every field that is set has to match.
+#[derive(Debug, Default, Clone)]
+pub struct MessageFilter {
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    // both directions of a direct conversation between two users
+    pub conversation: Option<(String, String)>,
+    // inclusive lower and exclusive upper bound on the timestamp
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl MessageFilter {
+    fn where_clause(&self) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(sender) = &self.sender {
+            conditions.push("username = ?");
+            values.push(Value::from(sender.clone()));
+        }
+        if let Some(recipient) = &self.recipient {
+            conditions.push("recipient = ?");
+            values.push(Value::from(recipient.clone()));
+        }
+        if let Some((first, second)) = &self.conversation {
+            conditions.push("((username = ? AND recipient = ?) OR (username = ? AND recipient = ?))");
+            values.extend([first, second, second, first].map(|user| Value::from(user.clone())));
+        }
+        if let Some(since) = self.since {
+            conditions.push("CAST(timestamp AS INTEGER) >= ?");
+            values.push(Value::from(since));
+        }
+        if let Some(until) = self.until {
+            conditions.push("CAST(timestamp AS INTEGER) < ?");
+            values.push(Value::from(until));
+        }
+        if conditions.is_empty() {
+            conditions.push("1 = 1");
+        }
+        (conditions.join(" AND "), values)
+    }
+}
+
+// qualified, so queries joining other tables with the same column names can use it too
+const MESSAGE_COLUMNS: &str = "messages.id, CAST(messages.timestamp AS INTEGER), messages.username, messages.recipient, messages.message, messages.parent_id";
+
+fn stored_message_from_row(row: &rusqlite::Row) -> Result<StoredMessage> {
+    let message: String = row.get(4)?;
+    Ok(StoredMessage {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        sender: row.get(2)?,
+        recipient: row.get(3)?,
+        // messages are stored with the line ending they arrived with
+        message: message.trim_end_matches(['\r', '\n']).to_string(),
+        parent_id: row.get(5)?,
+    })
+}
+
+/// One page of the messages matching `filter`, newest first.
+pub fn find_messages(filter: &MessageFilter, limit: i64, offset: i64) -> Result<Vec<StoredMessage>> {
+    let _timer = db_timer("find_messages");
+    let conn = get_db_conn()?;
+    let (condition, mut values) = filter.where_clause();
+    values.extend([Value::from(limit), Value::from(offset)]);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE {} ORDER BY id DESC LIMIT ? OFFSET ?",
+        MESSAGE_COLUMNS, condition
+    ))?;
+    let messages = stmt.query_map(params_from_iter(values), stored_message_from_row)?;
+    messages.collect()
+}
+
+pub fn count_messages(filter: &MessageFilter) -> Result<i64> {
+    let _timer = db_timer("count_messages");
+    let conn = get_db_conn()?;
+    let (condition, values) = filter.where_clause();
+    conn.query_row(&format!("SELECT COUNT(*) FROM messages WHERE {}", condition), params_from_iter(values), |row| row.get(0))
+}
+
+/// Up to `limit` messages matching `filter` with an id above `after_id`, oldest first.
+/// Exports walk the whole table with this instead of loading it at once.
+pub fn export_messages_after(filter: &MessageFilter, after_id: i64, limit: i64) -> Result<Vec<StoredMessage>> {
+    let _timer = db_timer("export_messages");
+    let conn = get_db_conn()?;
+    let (condition, mut values) = filter.where_clause();
+    values.extend([Value::from(after_id), Value::from(limit)]);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages WHERE {} AND id > ? ORDER BY id ASC LIMIT ?",
+        MESSAGE_COLUMNS, condition
+    ))?;
+    let messages = stmt.query_map(params_from_iter(values), stored_message_from_row)?;
+    messages.collect()
+}
+
+/// Deletes every message sent before `timestamp` and everything referring to them, returns how many messages.
+/// Replies to a purged message are kept as plain messages. The hourly counts in message_stats are kept,
+/// so statistics still cover purged history.
+pub fn purge_messages_before(timestamp: i64) -> Result<usize> {
+    let _timer = db_timer("purge_messages");
+    let mut conn = get_db_conn()?;
+    let transaction = conn.transaction()?;
+    const PURGED: &str = "SELECT id FROM messages WHERE CAST(timestamp AS INTEGER) < ?1";
+    for table in ["mentions", "reactions", "pins"] {
+        transaction.execute(&format!("DELETE FROM {} WHERE message_id IN ({})", table, PURGED), params![timestamp])?;
+    }
+    // every message left is newer, so a marker on a purged one counts the same as no marker
+    transaction.execute(&format!("DELETE FROM read_markers WHERE last_read_id IN ({})", PURGED), params![timestamp])?;
+    transaction.execute(
+        &format!("UPDATE messages SET parent_id = NULL WHERE parent_id IN ({}) AND CAST(timestamp AS INTEGER) >= ?1", PURGED),
+        params![timestamp],
+    )?;
+    let deleted = transaction.execute("DELETE FROM messages WHERE CAST(timestamp AS INTEGER) < ?1", params![timestamp])?;
+    transaction.commit()?;
+    Ok(deleted)
+}
+
+/// Writes a consistent copy of the database to `path`, safe to run while the server is using it.
+pub fn backup_db(path: &Path) -> Result<()> {
+    let conn = get_db_conn()?;
+    conn.backup(DatabaseName::Main, path, None)
+}
+
+/// Replaces the whole database with the one at `path`.
+pub fn restore_db(path: &Path) -> Result<()> {
+    let mut conn = get_db_conn()?;
+    conn.restore(DatabaseName::Main, path, None::<fn(rusqlite::backup::Progress)>)
+}
+
+pub fn vacuum_db() -> Result<()> {
+    get_db_conn()?.execute_batch("VACUUM")
+}
+
+/// Messages addressed to the user, to global or to one of the rooms, newer than the message `after_id`.
+pub fn get_messages_since(username: &str, rooms: &[String], after_id: i64, limit: i64) -> Result<Vec<StoredMessage>> {
+    let _timer = db_timer("get_messages_since");
+    let conn = get_db_conn()?;
+    let rooms_json = serde_json::to_string(rooms).unwrap_or_else(|_| "[]".to_string());
+    let mut stmt = conn.prepare(&format!("
+        SELECT {} FROM (
+            SELECT * FROM messages
+            WHERE id > ?1
+            AND (recipient = 'global' OR recipient = ?2 OR recipient IN (SELECT value FROM json_each(?3)))
+            ORDER BY id DESC LIMIT ?4
+        ) AS messages ORDER BY id ASC", MESSAGE_COLUMNS))?;
+
+    let messages = stmt.query_map(params![after_id, username, rooms_json, limit], stored_message_from_row)?;
+
+    messages.collect()
+}
+
+/// The id of the newest message, 0 without any.
+pub fn get_last_message_id() -> Result<i64> {
+    let _timer = db_timer("get_last_message_id");
+    let conn = get_db_conn()?;
+    conn.query_row("SELECT COALESCE(MAX(id), 0) FROM messages", [], |row| row.get(0))
+}
+
+pub fn get_message(id: i64) -> Result<Option<StoredMessage>> {
+    let _timer = db_timer("get_message");
+    let conn = get_db_conn()?;
+    conn.query_row(
+        &format!("SELECT {} FROM messages WHERE id = ?1", MESSAGE_COLUMNS),
+        params![id],
+        stored_message_from_row,
+    ).optional()
+}
+
+/// Records that `username` read `conversation` up to `message_id`, read markers never move back.
+pub fn mark_read(username: &str, conversation: &str, message_id: i64) -> Result<()> {
+    let _timer = db_timer("mark_read");
+    let conn = get_db_conn()?;
+    conn.execute(
+        "INSERT INTO read_markers (username, conversation, last_read_id) VALUES (?1, ?2, ?3)
+         ON CONFLICT(username, conversation) DO UPDATE SET last_read_id = MAX(last_read_id, excluded.last_read_id)",
+        params![username, conversation, message_id],
+    )?;
+    Ok(())
+}
+
+/// Messages others sent to `username`, global or one of `rooms` after the read marker, counted per conversation.
+/// Direct messages are counted under their sender.
+pub fn count_unread(username: &str, rooms: &[String]) -> Result<Vec<(String, i64)>> {
+    let _timer = db_timer("count_unread");
+    let conn = get_db_conn()?;
+    let rooms_json = serde_json::to_string(rooms).unwrap_or_else(|_| "[]".to_string());
+    let mut stmt = conn.prepare("
+        SELECT unread.conversation, COUNT(*) FROM (
+            SELECT id, CASE WHEN recipient = ?1 THEN username ELSE recipient END AS conversation FROM messages
+            WHERE username != ?1
+            AND (recipient = ?1 OR recipient = 'global' OR recipient IN (SELECT value FROM json_each(?2)))
+        ) AS unread
+        LEFT JOIN read_markers ON read_markers.username = ?1 AND read_markers.conversation = unread.conversation
+        WHERE unread.id > COALESCE(read_markers.last_read_id, 0)
+        GROUP BY unread.conversation
+        ORDER BY unread.conversation")?;
+    let counts = stmt.query_map(params![username, rooms_json], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    counts.collect()
+}
+
+/// The first message of a thread and the `limit` most recent replies in it, oldest first.
+pub fn get_thread(root_id: i64, limit: i64) -> Result<Vec<StoredMessage>> {
+    let _timer = db_timer("get_thread");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare(&format!("
+        SELECT {} FROM messages WHERE id = ?1
+        UNION ALL
+        SELECT * FROM (SELECT {} FROM messages WHERE parent_id = ?1 ORDER BY id DESC LIMIT ?2)
+        ORDER BY 1 ASC", MESSAGE_COLUMNS, MESSAGE_COLUMNS))?;
+    let messages = stmt.query_map(params![root_id, limit], stored_message_from_row)?;
+    messages.collect()
+}
+
+/// The topic of a room or global chat.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Topic {
+    pub topic: String,
+    pub set_by: Option<String>,
+    pub set_at: Option<i64>,
+}
+
+pub fn get_topic(conversation: &str) -> Result<Option<Topic>> {
+    let _timer = db_timer("get_topic");
+    let conn = get_db_conn()?;
+    conn.query_row(
+        "SELECT topic, set_by, set_at FROM topics WHERE conversation = ?1",
+        params![conversation],
+        |row| Ok(Topic { topic: row.get(0)?, set_by: row.get(1)?, set_at: row.get(2)? }),
+    ).optional()
+}
+
+/// Sets the topic of `conversation`, None removes it.
+pub fn set_topic(conversation: &str, topic: Option<&str>, set_by: &str, timestamp: i64) -> Result<()> {
+    let _timer = db_timer("set_topic");
+    let conn = get_db_conn()?;
+    match topic {
+        Some(topic) => conn.execute(
+            "INSERT INTO topics (conversation, topic, set_by, set_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(conversation) DO UPDATE SET topic = excluded.topic, set_by = excluded.set_by, set_at = excluded.set_at",
+            params![conversation, topic, set_by, timestamp],
+        )?,
+        None => conn.execute("DELETE FROM topics WHERE conversation = ?1", params![conversation])?,
+    };
+    Ok(())
+}
+
+/// The messages pinned in `conversation`, in the order they were pinned.
+pub fn get_pins(conversation: &str) -> Result<Vec<StoredMessage>> {
+    let _timer = db_timer("get_pins");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare(&format!("
+        SELECT {} FROM pins
+        JOIN messages ON messages.id = pins.message_id
+        WHERE pins.conversation = ?1
+        ORDER BY pins.pinned_at, pins.rowid", MESSAGE_COLUMNS))?;
+    let messages = stmt.query_map(params![conversation], stored_message_from_row)?;
+    messages.collect()
+}
+
+/// Pins a message, returns false if it already was.
+pub fn add_pin(conversation: &str, message_id: i64, pinned_by: &str, timestamp: i64) -> Result<bool> {
+    let _timer = db_timer("add_pin");
+    let conn = get_db_conn()?;
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO pins (conversation, message_id, pinned_by, pinned_at) VALUES (?1, ?2, ?3, ?4)",
+        params![conversation, message_id, pinned_by, timestamp],
+    )?;
+    Ok(added > 0)
+}
+
+/// Unpins a message, returns false if it was not pinned.
+pub fn remove_pin(conversation: &str, message_id: i64) -> Result<bool> {
+    let _timer = db_timer("remove_pin");
+    let conn = get_db_conn()?;
+    let removed = conn.execute("DELETE FROM pins WHERE conversation = ?1 AND message_id = ?2", params![conversation, message_id])?;
+    Ok(removed > 0)
+}
+
+/// A room created by joining it, global is not one.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Room {
+    pub name: String,
+    pub owner: String,
+    pub invite_only: bool,
+    pub hidden: bool,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub created_at: i64,
+}
+
+const ROOM_COLUMNS: &str = "name, owner, invite_only, hidden, password_hash, created_at";
+
+fn room_from_row(row: &rusqlite::Row) -> Result<Room> {
+    Ok(Room {
+        name: row.get(0)?,
+        owner: row.get(1)?,
+        invite_only: row.get(2)?,
+        hidden: row.get(3)?,
+        password_hash: row.get(4)?,
+        created_at: row.get::<_, Option<i64>>(5)?.unwrap_or_default(),
+    })
+}
+
+/// Creates a room owned by `room.owner`, returns false if it already exists.
+pub fn create_room(room: &Room) -> Result<bool> {
+    let _timer = db_timer("create_room");
+    let mut conn = get_db_conn()?;
+    let transaction = conn.transaction()?;
+    let created = transaction.execute(
+        &format!("INSERT OR IGNORE INTO rooms ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", ROOM_COLUMNS),
+        params![room.name, room.owner, room.invite_only, room.hidden, room.password_hash, room.created_at],
+    )? > 0;
+    if created {
+        transaction.execute(
+            "INSERT OR REPLACE INTO room_access (room, username, role, changed_by, changed_at) VALUES (?1, ?2, 'owner', ?2, ?3)",
+            params![room.name, room.owner, room.created_at],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(created)
+}
+
+pub fn get_room(name: &str) -> Result<Option<Room>> {
+    let _timer = db_timer("get_room");
+    let conn = get_db_conn()?;
+    conn.query_row(&format!("SELECT {} FROM rooms WHERE name = ?1", ROOM_COLUMNS), params![name], room_from_row).optional()
+}
+
+pub fn get_rooms() -> Result<Vec<Room>> {
+    let _timer = db_timer("get_rooms");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM rooms ORDER BY name", ROOM_COLUMNS))?;
+    let rooms = stmt.query_map([], room_from_row)?;
+    rooms.collect()
+}
+
+/// Stores the invite-only, hidden and password settings of a room.
+pub fn update_room(room: &Room) -> Result<()> {
+    let _timer = db_timer("update_room");
+    let conn = get_db_conn()?;
+    conn.execute(
+        "UPDATE rooms SET invite_only = ?2, hidden = ?3, password_hash = ?4 WHERE name = ?1",
+        params![room.name, room.invite_only, room.hidden, room.password_hash],
+    )?;
+    Ok(())
+}
+
+pub fn get_room_role(room: &str, username: &str) -> Result<Option<String>> {
+    let _timer = db_timer("get_room_role");
+    let conn = get_db_conn()?;
+    conn.query_row(
+        "SELECT role FROM room_access WHERE room = ?1 AND username = ?2",
+        params![room, username],
+        |row| row.get(0),
+    ).optional()
+}
+
+/// Gives `username` a role in `room`, None takes it away.
+pub fn set_room_role(room: &str, username: &str, role: Option<&str>, changed_by: &str, timestamp: i64) -> Result<()> {
+    let _timer = db_timer("set_room_role");
+    let conn = get_db_conn()?;
+    match role {
+        Some(role) => conn.execute(
+            "INSERT INTO room_access (room, username, role, changed_by, changed_at) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(room, username) DO UPDATE SET role = excluded.role, changed_by = excluded.changed_by, changed_at = excluded.changed_at",
+            params![room, username, role, changed_by, timestamp],
+        )?,
+        None => conn.execute("DELETE FROM room_access WHERE room = ?1 AND username = ?2", params![room, username])?,
+    };
+    Ok(())
+}
+
+/// Everyone with a role in `room`, as (username, role).
+pub fn get_room_access(room: &str) -> Result<Vec<(String, String)>> {
+    let _timer = db_timer("get_room_access");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare("SELECT username, role FROM room_access WHERE room = ?1 ORDER BY username")?;
+    let access = stmt.query_map(params![room], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    access.collect()
+}
+
+/// The rooms `username` holds one of `roles` in.
+pub fn get_rooms_with_role(username: &str, roles: &[&str]) -> Result<Vec<String>> {
+    let _timer = db_timer("get_rooms_with_role");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare("SELECT room, role FROM room_access WHERE username = ?1 ORDER BY room")?;
+    let rows = stmt.query_map(params![username], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    let mut rooms = Vec::new();
+    for row in rows {
+        let (room, role) = row?;
+        if roles.contains(&role.as_str()) {
+            rooms.push(room);
+        }
+    }
+    Ok(rooms)
+}
+
+/// A text setting kept in server_data, like the MOTD changed through the web UI.
+pub fn get_server_setting(key: &str) -> Result<Option<String>> {
+    let _timer = db_timer("get_server_setting");
+    let conn = get_db_conn()?;
+    conn.query_row("SELECT value FROM server_data WHERE key = ?1", params![key], |row| row.get(0)).optional()
+}
+
+/// Stores a text setting in server_data, None removes it.
+pub fn set_server_setting(key: &str, value: Option<&str>) -> Result<()> {
+    let _timer = db_timer("set_server_setting");
+    let conn = get_db_conn()?;
+    match value {
+        Some(value) => conn.execute(
+            "INSERT INTO server_data (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = ?2",
+            params![key, value],
+        )?,
+        None => conn.execute("DELETE FROM server_data WHERE key = ?1", params![key])?,
+    };
+    Ok(())
+}
+
+/// The hash of the rules `username` last accepted.
+pub fn get_acknowledged_rules(username: &str) -> Result<Option<String>> {
+    let _timer = db_timer("get_acknowledged_rules");
+    let conn = get_db_conn()?;
+    conn.query_row(
+        "SELECT rules_hash FROM rules_acknowledgements WHERE username = ?1",
+        params![username],
+        |row| row.get(0),
+    ).optional()
+}
+
+pub fn acknowledge_rules(username: &str, rules_hash: &str, timestamp: i64) -> Result<()> {
+    let _timer = db_timer("acknowledge_rules");
+    let conn = get_db_conn()?;
+    conn.execute(
+        "INSERT INTO rules_acknowledgements (username, rules_hash, acknowledged_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT(username) DO UPDATE SET rules_hash = excluded.rules_hash, acknowledged_at = excluded.acknowledged_at",
+        params![username, rules_hash, timestamp],
+    )?;
+    Ok(())
+}
+
+/// Records that message `message_id` mentions `username`, `notified` once they were sent a MENTION frame for it.
+pub fn add_mention(message_id: i64, username: &str, notified: bool) -> Result<()> {
+    let _timer = db_timer("add_mention");
+    let conn = get_db_conn()?;
+    conn.execute(
+        "INSERT OR IGNORE INTO mentions (message_id, username, notified) VALUES (?1, ?2, ?3)",
+        params![message_id, username, notified],
+    )?;
+    Ok(())
+}
+
+/// The `limit` most recent messages mentioning `username`, newest first.
+pub fn get_mentions(username: &str, limit: i64) -> Result<Vec<StoredMessage>> {
+    let _timer = db_timer("get_mentions");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare(&format!("
+        SELECT {} FROM mentions
+        JOIN messages ON messages.id = mentions.message_id
+        WHERE mentions.username = ?1
+        ORDER BY id DESC LIMIT ?2", MESSAGE_COLUMNS))?;
+    let messages = stmt.query_map(params![username, limit], stored_message_from_row)?;
+    messages.collect()
+}
+
+/// Mentions of `username` they were not notified of yet, oldest first. They count as notified afterwards.
+pub fn take_unnotified_mentions(username: &str) -> Result<Vec<StoredMessage>> {
+    let _timer = db_timer("take_unnotified_mentions");
+    let mut conn = get_db_conn()?;
+    let transaction = conn.transaction()?;
+    let messages = {
+        let mut stmt = transaction.prepare(&format!("
+            SELECT {} FROM mentions
+            JOIN messages ON messages.id = mentions.message_id
+            WHERE mentions.username = ?1 AND notified = 0
+            ORDER BY id ASC", MESSAGE_COLUMNS))?;
+        let messages = stmt.query_map(params![username], stored_message_from_row)?;
+        messages.collect::<Result<Vec<_>>>()?
+    };
+    transaction.execute("UPDATE mentions SET notified = 1 WHERE username = ?1 AND notified = 0", params![username])?;
+    transaction.commit()?;
+    Ok(messages)
+}
+
+/// Blocks `blocked` for `username`, returns false if they already were.
+pub fn add_block(username: &str, blocked: &str, timestamp: i64) -> Result<bool> {
+    let _timer = db_timer("add_block");
+    let conn = get_db_conn()?;
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO blocks (username, blocked, blocked_at) VALUES (?1, ?2, ?3)",
+        params![username, blocked, timestamp],
+    )?;
+    Ok(added > 0)
+}
+
+/// Unblocks `blocked` for `username`, returns false if they were not blocked.
+pub fn remove_block(username: &str, blocked: &str) -> Result<bool> {
+    let _timer = db_timer("remove_block");
+    let conn = get_db_conn()?;
+    let removed = conn.execute("DELETE FROM blocks WHERE username = ?1 AND blocked = ?2", params![username, blocked])?;
+    Ok(removed > 0)
+}
+
+/// The users `username` blocked.
+pub fn get_blocked(username: &str) -> Result<Vec<String>> {
+    let _timer = db_timer("get_blocked");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare("SELECT blocked FROM blocks WHERE username = ?1 ORDER BY blocked")?;
+    let blocked = stmt.query_map(params![username], |row| row.get(0))?;
+    blocked.collect()
+}
+
+/// The users that blocked `blocked`.
+pub fn get_blockers(blocked: &str) -> Result<Vec<String>> {
+    let _timer = db_timer("get_blockers");
+    let conn = get_db_conn()?;
+    let mut stmt = conn.prepare("SELECT username FROM blocks WHERE blocked = ?1")?;
+    let blockers = stmt.query_map(params![blocked], |row| row.get(0))?;
+    blockers.collect()
+}
+
+/// Adds a reaction of `username` to a message, returns false if they already reacted with it.
+pub fn add_reaction(message_id: i64, username: &str, reaction: &str, timestamp: i64) -> Result<bool> {
+    let _timer = db_timer("add_reaction");
+    let conn = get_db_conn()?;
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO reactions (message_id, username, reaction, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![message_id, username, reaction, timestamp],
+    )?;
+    Ok(added > 0)
+}
+
+/// How often each reaction was given, for every message in `message_ids` that has any, in the order they were first given.
+pub fn get_reaction_counts(message_ids: &[i64]) -> Result<HashMap<i64, Vec<(String, i64)>>> {
+    let _timer = db_timer("get_reaction_counts");
+    let conn = get_db_conn()?;
+    let ids_json = serde_json::to_string(message_ids).unwrap_or_else(|_| "[]".to_string());
+    let mut stmt = conn.prepare("
+        SELECT message_id, reaction, COUNT(*) FROM reactions
+        WHERE message_id IN (SELECT value FROM json_each(?1))
+        GROUP BY message_id, reaction
+        ORDER BY message_id, MIN(created_at), MIN(rowid)")?;
+    let mut counts: HashMap<i64, Vec<(String, i64)>> = HashMap::new();
+    let rows = stmt.query_map(params![ids_json], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    for row in rows {
+        let (message_id, reaction, count) = row?;
+        counts.entry(message_id).or_default().push((reaction, count));
+    }
+    Ok(counts)
+}
+
+pub fn add_resume_token(token_hash: &str, username: &str) -> Result<()> {
+    let _timer = db_timer("add_resume_token");
+    let conn = get_db_conn()?;
+    conn.execute(
+        "INSERT INTO resume_tokens (token_hash, username, issued_at) VALUES (?1, ?2, ?3)",
+        params![token_hash, username, Utc::now().timestamp()],
+    )?;
+    Ok(())
+}
+
+pub fn set_resume_token_disconnected(token_hash: &str, disconnected_at: i64, rooms: &[String], last_message_id: i64) -> Result<()> {
+    let _timer = db_timer("set_resume_token_disconnected");
+    let conn = get_db_conn()?;
+    let rooms_json = serde_json::to_string(rooms).unwrap_or_else(|_| "[]".to_string());
+    conn.execute(
+        "UPDATE resume_tokens SET disconnected_at = ?1, rooms = ?2, last_message_id = ?3 WHERE token_hash = ?4",
+        params![disconnected_at, rooms_json, last_message_id, token_hash],
+    )?;
+    Ok(())
+}
+
+pub fn delete_resume_token(token_hash: &str) -> Result<()> {
+    let _timer = db_timer("delete_resume_token");
+    let conn = get_db_conn()?;
+    conn.execute("DELETE FROM resume_tokens WHERE token_hash = ?1", params![token_hash])?;
+    Ok(())
+}
+
+/// A resume token of a session that disconnected.
+pub struct SuspendedSession {
+    pub disconnected_at: i64,
+    pub rooms: Vec<String>,
+    // the newest message the session was sent
+    pub last_message_id: i64,
+}
+
+/// Removes the token and returns the session it belongs to. Tokens are single use, whether or not
+/// the caller ends up accepting them, but the token of a session that is still connected is left alone.
+pub fn take_resume_token(token_hash: &str, username: &str) -> Result<Option<SuspendedSession>> {
+    let _timer = db_timer("take_resume_token");
+    let conn = get_db_conn()?;
+    // tokens suspended before last_message_id was recorded fall back to the time of the disconnect
+    let row = conn.query_row(
+        "DELETE FROM resume_tokens WHERE token_hash = ?1 AND username = ?2 AND disconnected_at IS NOT NULL
+         RETURNING disconnected_at, rooms, last_message_id",
+        params![token_hash, username],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, i64>(2)?)),
+    );
+
+    match row {
+        Ok((disconnected_at, rooms, last_message_id)) => {
+            let rooms = rooms.and_then(|rooms| serde_json::from_str(&rooms).ok()).unwrap_or_default();
+            Ok(Some(SuspendedSession { disconnected_at, rooms, last_message_id }))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Drops tokens that went unused for longer than the resume window, and tokens of sessions that
+/// never recorded a disconnect (the server was killed) after a week.
+pub fn purge_expired_resume_tokens(window: i64) -> Result<usize> {
+    let _timer = db_timer("purge_expired_resume_tokens");
+    let conn = get_db_conn()?;
+    let now = Utc::now().timestamp();
+    conn.execute(
+        "DELETE FROM resume_tokens WHERE disconnected_at < ?1 OR (disconnected_at IS NULL AND issued_at < ?2)",
+        params![now - window, now - 7 * 24 * 60 * 60],
+    )
+}
+
+/// A fresh database for a test that knows `usernames`.
+#[cfg(test)]
+pub fn seed_test_users(usernames: &[&str]) {
+    init_db().unwrap();
+    for username in usernames {
+        add_or_update_user(username);
+    }
+}
+
+/// An open room `name` owned by `owner`, for tests.
+#[cfg(test)]
+pub fn seed_test_room(name: &str, owner: &str) {
+    let room = Room {
+        name: name.to_string(),
+        owner: owner.to_string(),
+        invite_only: false,
+        hidden: false,
+        password_hash: None,
+        created_at: 100,
+    };
+    assert!(create_room(&room).unwrap());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,16 +1132,148 @@ mod tests {
 
     #[test]
     fn test_add_message_and_fetch() {
+        init_db().unwrap();
         let test_username = "testuser";
 
         add_or_update_user(test_username);
         let timestamp = Utc::now().timestamp_millis();
-        add_message_to_db(timestamp, test_username, "global", "Hello, world!").unwrap();
+        add_message_to_db(timestamp, test_username, "global", "Hello, world!", None).unwrap();
 
         let messages = get_messages("global", 10).unwrap();
 
         assert!(!messages.is_empty());
         assert_eq!(messages.len(), 1);
-        assert!(messages[0].contains("Hello, world!"));
+        assert_eq!(messages[0].message, "Hello, world!");
+    }
+
+    #[test]
+    fn test_migrations_and_profiles() {
+        init_db().unwrap();
+        // already up to date after init_db
+        assert_eq!(migrate(&get_db_conn().unwrap()).unwrap(), 0);
+
+        add_or_update_user("alice");
+        let user = get_user("alice").unwrap().unwrap();
+        assert_eq!(user.profile, UserProfile::default());
+        assert_eq!(user.profile_synced_at, None);
+
+        let profile = UserProfile {
+            display_name: Some("Alice".to_string()),
+            calculator: Some("TI-84 Plus CE".to_string()),
+            flags: vec!["staff".to_string()],
+        };
+        update_user_profile("alice", &profile, 1000).unwrap();
+        let user = get_user("alice").unwrap().unwrap();
+        assert_eq!(user.profile, profile);
+        assert_eq!(user.profile_synced_at, Some(1000));
+        assert!(get_user("nobody").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_server_statistics() {
+        init_db().unwrap();
+        record_online_users(3, 1000).unwrap();
+        record_online_users(2, 2000).unwrap();
+        let conn = get_db_conn().unwrap();
+        let peak: (String, String) = conn.query_row(
+            "SELECT (SELECT value FROM server_data WHERE key = 'peak_concurrent_users'), (SELECT value FROM server_data WHERE key = 'peak_concurrent_users_at')",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!(peak, ("3".to_string(), "1000".to_string()));
+
+        add_or_update_user("alice");
+        add_or_update_user("bob");
+        add_or_update_user("lurker");
+        add_message_to_db(100, "alice", "global", "one", None).unwrap();
+        add_message_to_db(200, "bob", "global", "two", None).unwrap();
+        add_message_to_db(7300, "bob", "global", "three", None).unwrap();
+        assert_eq!(count_registered_users().unwrap(), 3);
+        assert_eq!(get_top_posters(10).unwrap(), vec![("bob".to_string(), 2), ("alice".to_string(), 1)]);
+        assert_eq!(get_message_counts(3600, 0).unwrap(), vec![(0, 2), (7200, 1)]);
+    }
+
+    #[test]
+    fn test_find_messages_with_filters() {
+        seed_test_users(&["alice", "bob", "carol"]);
+        add_message_to_db(100, "alice", "global", "hello everyone", None).unwrap();
+        add_message_to_db(200, "alice", "bob", "hi bob", None).unwrap();
+        add_message_to_db(300, "bob", "alice", "hi alice", None).unwrap();
+        add_message_to_db(400, "carol", "bob", "hey", None).unwrap();
+
+        let all = MessageFilter::default();
+        assert_eq!(count_messages(&all).unwrap(), 4);
+        let page = find_messages(&all, 2, 1).unwrap();
+        assert_eq!(page.iter().map(|message| message.timestamp).collect::<Vec<_>>(), vec![300, 200]);
+
+        let filter = MessageFilter { sender: Some("alice".to_string()), since: Some(150), ..Default::default() };
+        let messages = find_messages(&filter, 10, 0).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message, "hi bob");
+
+        let filter = MessageFilter { conversation: Some(("bob".to_string(), "alice".to_string())), ..Default::default() };
+        assert_eq!(count_messages(&filter).unwrap(), 2);
+
+        let filter = MessageFilter { recipient: Some("bob".to_string()), until: Some(400), ..Default::default() };
+        assert_eq!(count_messages(&filter).unwrap(), 1);
+
+        let first_batch = export_messages_after(&all, 0, 3).unwrap();
+        assert_eq!(first_batch.len(), 3);
+        let rest = export_messages_after(&all, first_batch[2].id, 3).unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].sender, "carol");
+    }
+
+    #[test]
+    fn test_user_admin_and_backup() {
+        seed_test_users(&["alice", "bob"]);
+        add_message_to_db(100, "alice", "global", "old", None).unwrap();
+        add_message_to_db(200, "bob", "global", "new", None).unwrap();
+
+        assert!(set_user_permission("alice", "admin").unwrap());
+        assert!(!set_user_permission("nobody", "admin").unwrap());
+        assert!(set_user_banned("bob", true, Some("spam")).unwrap());
+        assert_eq!(get_ban("bob").unwrap(), Some("spam".to_string()));
+        assert_eq!(get_ban("alice").unwrap(), None);
+        // banned before they ever logged in
+        assert!(set_user_banned("carol", true, None).unwrap());
+        assert_eq!(get_ban("carol").unwrap(), Some(String::new()));
+        assert!(!set_user_banned("nobody", false, None).unwrap());
+        let users = list_users().unwrap();
+        assert_eq!(users.iter().map(|user| user.username.as_str()).collect::<Vec<_>>(), vec!["alice", "bob", "carol"]);
+        assert_eq!(users[0].permission, "admin");
+        assert!(users[1].banned);
+        assert_eq!(users[2].permission, "user");
+
+        let backup = std::env::temp_dir().join(format!("netchat-backup-test-{}.db", std::process::id()));
+        backup_db(&backup).unwrap();
+
+        assert_eq!(purge_messages_before(150).unwrap(), 1);
+        assert!(set_user_banned("bob", false, Some("ignored")).unwrap());
+        assert_eq!(get_ban("bob").unwrap(), None);
+
+        restore_db(&backup).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+        assert_eq!(count_messages(&MessageFilter::default()).unwrap(), 2);
+        assert_eq!(get_ban("bob").unwrap(), Some("spam".to_string()));
+    }
+
+    #[test]
+    fn test_purge_removes_dependent_rows() {
+        seed_test_users(&["alice", "bob"]);
+        let old = add_message_to_db(100, "alice", "global", "old @bob", None).unwrap();
+        let reply = add_message_to_db(200, "bob", "global", "a reply", Some(old)).unwrap();
+        add_mention(old, "bob", false).unwrap();
+        add_reaction(old, "bob", "+1", 100).unwrap();
+        add_pin("global", old, "alice", 100).unwrap();
+        mark_read("bob", "global", old).unwrap();
+        mark_read("alice", "global", reply).unwrap();
+
+        assert_eq!(purge_messages_before(150).unwrap(), 1);
+        let conn = get_db_conn().unwrap();
+        let count = |table: &str| conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!((count("mentions"), count("reactions"), count("pins")), (0, 0, 0));
+        assert_eq!(count("read_markers"), 1);
+        assert_eq!(get_message(reply).unwrap().unwrap().parent_id, None);
     }
 }
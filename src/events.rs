@@ -0,0 +1,120 @@
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::stream::{self, Stream};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// events a slow dashboard may fall behind before it starts missing some
+const BUS_CAPACITY: usize = 1024;
+
+static REDACT_MESSAGES: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref EVENT_BUS: broadcast::Sender<ServerEvent> = broadcast::channel(BUS_CAPACITY).0;
+}
+
+/// Something that happened on the server, as shown on the admin dashboard.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Connected { conn_id: u64, peer_addr: String },
+    Disconnected { conn_id: u64, username: Option<String> },
+    Auth { conn_id: u64, username: String, result: String },
+    Message { conn_id: u64, sender: String, recipient: String, message: String, timestamp: i64 },
+    Moderation { action: String, target: String, detail: String },
+}
+
+impl ServerEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::Connected { .. } => "connected",
+            ServerEvent::Disconnected { .. } => "disconnected",
+            ServerEvent::Auth { .. } => "auth",
+            ServerEvent::Message { .. } => "message",
+            ServerEvent::Moderation { .. } => "moderation",
+        }
+    }
+
+    fn redacted(mut self) -> Self {
+        if let ServerEvent::Message { message, .. } = &mut self {
+            *message = "[redacted]".to_string();
+        }
+        self
+    }
+}
+
+/// Hands the event to every open event stream, it is dropped when nobody is listening.
+pub fn publish(event: ServerEvent) {
+    let _ = EVENT_BUS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<ServerEvent> {
+    EVENT_BUS.subscribe()
+}
+
+/// Hides message bodies from the event stream, set from `web.redact_events`.
+pub fn set_redact_messages(redact: bool) {
+    REDACT_MESSAGES.store(redact, Ordering::Relaxed);
+}
+
+fn to_sse_event(event: ServerEvent) -> Event {
+    let event = if REDACT_MESSAGES.load(Ordering::Relaxed) { event.redacted() } else { event };
+    Event::default()
+        .event(event.name())
+        .json_data(&event)
+        .unwrap_or_else(|_| Event::default().event("error"))
+}
+
+/// `/api/events`, a Server-Sent Events stream of everything published on the bus.
+pub async fn events_handler() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = stream::unfold(subscribe(), |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => to_sse_event(event),
+            // tell the dashboard it missed events so it can reload everything
+            Err(RecvError::Lagged(missed)) => Event::default().event("lagged").data(missed.to_string()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), receiver))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_and_subscribe() {
+        let mut receiver = subscribe();
+        publish(ServerEvent::Moderation {
+            action: "kick".to_string(),
+            target: "events-test-user".to_string(),
+            detail: "test".to_string(),
+        });
+
+        // other tests may publish at the same time
+        loop {
+            let event = receiver.recv().await.unwrap();
+            let json = serde_json::to_value(&event).unwrap();
+            if json["target"] == "events-test-user" {
+                assert_eq!(event.name(), "moderation");
+                assert_eq!(json["type"], "moderation");
+                assert_eq!(json["action"], "kick");
+                break;
+            }
+        }
+
+        let event = ServerEvent::Message {
+            conn_id: 1,
+            sender: "alice".to_string(),
+            recipient: "global".to_string(),
+            message: "secret".to_string(),
+            timestamp: 0,
+        };
+        let json = serde_json::to_value(event.redacted()).unwrap();
+        assert_eq!(json["message"], "[redacted]");
+        assert_eq!(json["sender"], "alice");
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io;
+
+// set by the previous process on restart, a list of name:fd pairs
+const HANDOFF_ENV: &str = "NETCHAT_LISTEN_FDS";
+// systemd socket activation passes its sockets starting at this descriptor
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+#[cfg(unix)]
+pub use std::os::fd::AsRawFd as HandoffSocket;
+#[cfg(windows)]
+pub use std::os::windows::io::AsRawSocket as HandoffSocket;
+
+/// A listening socket passed on by a previous server process or by systemd.
+pub enum InheritedSocket {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Listening sockets inherited from a previous server process or from systemd, by name.
+/// Chat listeners are named in the config ("chat" by default) and the web UI listener is called "web".
+#[derive(Default)]
+pub struct InheritedListeners(HashMap<String, InheritedSocket>);
+
+impl InheritedListeners {
+    pub fn take_tcp(&mut self, name: &str) -> Option<std::net::TcpListener> {
+        match self.0.remove(name)? {
+            InheritedSocket::Tcp(listener) => Some(listener),
+            #[cfg(unix)]
+            InheritedSocket::Unix(_) => {
+                tracing::warn!(target: "handoff", "Inherited {} listener is a Unix socket, binding a new TCP one", name);
+                None
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn take_unix(&mut self, name: &str) -> Option<std::os::unix::net::UnixListener> {
+        match self.0.remove(name)? {
+            InheritedSocket::Unix(listener) => Some(listener),
+            InheritedSocket::Tcp(_) => {
+                tracing::warn!(target: "handoff", "Inherited {} listener is a TCP socket, binding a new Unix one", name);
+                None
+            }
+        }
+    }
+}
+
+/// Picks up listeners passed by `spawn_successor` or by systemd socket activation.
+#[cfg(unix)]
+pub fn inherited_listeners() -> InheritedListeners {
+    let fds = if let Ok(value) = std::env::var(HANDOFF_ENV) {
+        std::env::remove_var(HANDOFF_ENV);
+        parse_handoff_fds(&value)
+    } else if std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id()) {
+        let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse().ok()).unwrap_or(0);
+        let names = std::env::var("LISTEN_FDNAMES").ok();
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+        systemd_fds(count, names.as_deref())
+    } else {
+        Vec::new()
+    };
+
+    let mut listeners = HashMap::new();
+    for (name, fd) in fds {
+        match socket_from_fd(fd) {
+            Ok((socket, addr)) => {
+                tracing::info!(target: "handoff", "Inherited {} listener on {}", name, addr);
+                listeners.insert(name, socket);
+            }
+            Err(e) => tracing::error!(target: "handoff", "Inherited descriptor {} for {} is not a listener: {}", fd, name, e),
+        }
+    }
+    InheritedListeners(listeners)
+}
+
+// the descriptor is a TCP socket if it has an IP address, otherwise it should be a Unix one
+#[cfg(unix)]
+fn socket_from_fd(fd: i32) -> io::Result<(InheritedSocket, String)> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    // SAFETY: the descriptor was handed to us for exactly this purpose and nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if let Ok(addr) = listener.local_addr() {
+        listener.set_nonblocking(true)?;
+        return Ok((InheritedSocket::Tcp(listener), addr.to_string()));
+    }
+    // SAFETY: ownership moves straight from the TCP listener that was just given up
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(listener.into_raw_fd()) };
+    let addr = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    let path = addr.as_pathname().map_or_else(|| "an unnamed socket".to_string(), |path| path.display().to_string());
+    Ok((InheritedSocket::Unix(listener), format!("unix:{}", path)))
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listeners() -> InheritedListeners {
+    InheritedListeners::default()
+}
+
+fn parse_handoff_fds(value: &str) -> Vec<(String, i32)> {
+    value.split(',')
+        .filter_map(|pair| {
+            let (name, fd) = pair.split_once(':')?;
+            Some((name.trim().to_string(), fd.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn systemd_fds(count: i32, names: Option<&str>) -> Vec<(String, i32)> {
+    let names: Vec<&str> = names.map(|names| names.split(':').collect()).unwrap_or_default();
+    (0..count)
+        .map(|index| {
+            let default_name = if index == 0 { "chat" } else { "web" };
+            let name = names.get(index as usize).copied().filter(|name| !name.is_empty()).unwrap_or(default_name);
+            (name.to_string(), SD_LISTEN_FDS_START + index)
+        })
+        .collect()
+}
+
+/// Resolves when the server is asked to restart in place, on SIGUSR2.
+pub async fn wait_for_restart_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::user_defined2()) {
+            Ok(mut sigusr2) => {
+                sigusr2.recv().await;
+                return;
+            }
+            Err(e) => tracing::error!(target: "handoff", "Failed to listen for SIGUSR2: {}", e),
+        }
+    }
+    std::future::pending::<()>().await
+}
+
+/// Starts a fresh copy of the server that inherits the given listening sockets.
+/// Fails if the new process cannot be started or exits right away, so the caller can keep serving.
+#[cfg(unix)]
+pub async fn spawn_successor(listeners: &[(&str, &dyn HandoffSocket)]) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let fds: Vec<i32> = listeners.iter().map(|(_, listener)| listener.as_raw_fd()).collect();
+    let value = listeners.iter()
+        .zip(&fds)
+        .map(|((name, _), fd)| format!("{}:{}", name, fd))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command.args(std::env::args_os().skip(1))
+        .env(HANDOFF_ENV, value)
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES");
+    // SAFETY: only calls fcntl, which is async-signal-safe, between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &fds {
+                // std opens every socket with FD_CLOEXEC, clear it in the child so the listener survives exec
+                if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    if let Some(status) = child.try_wait()? {
+        return Err(io::Error::other(format!("new server process exited early: {}", status)));
+    }
+    tracing::info!(target: "handoff", "Handed listeners over to new server process {}", child.id());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn spawn_successor(_listeners: &[(&str, &dyn HandoffSocket)]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "listener handoff is only supported on unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_handoff_fds() {
+        assert_eq!(parse_handoff_fds("chat:5,web:7"), vec![("chat".to_string(), 5), ("web".to_string(), 7)]);
+        assert_eq!(parse_handoff_fds("chat:5,broken,web:x"), vec![("chat".to_string(), 5)]);
+        assert!(parse_handoff_fds("").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_systemd_fds() {
+        assert_eq!(systemd_fds(2, None), vec![("chat".to_string(), 3), ("web".to_string(), 4)]);
+        assert_eq!(systemd_fds(1, Some("web")), vec![("web".to_string(), 3)]);
+        assert!(systemd_fds(0, None).is_empty());
+    }
+}
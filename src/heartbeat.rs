@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+use crate::config::ConnectionConfig;
+use crate::state::sends_events;
+
+/// What the connection handler has to do to keep a connection honest.
+#[derive(Debug, PartialEq)]
+pub enum Liveness {
+    Alive,
+    // send a PING, the client answers with PONG
+    Ping,
+    Close(&'static str),
+}
+
+/// Tracks when a connection was last heard from.
+pub struct Heartbeat {
+    connected_at: Instant,
+    last_received: Instant,
+    last_ping: Option<Instant>,
+}
+
+fn seconds(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value))
+}
+
+impl Heartbeat {
+    pub fn new(now: Instant) -> Self {
+        Heartbeat { connected_at: now, last_received: now, last_ping: None }
+    }
+
+    /// Anything the client sends counts as a sign of life.
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    /// Heartbeats only go to protocol 2 clients, older ones would take a PING for garbage.
+    pub fn check(&mut self, config: &ConnectionConfig, authenticated: bool, protocol_version: u8, now: Instant) -> Liveness {
+        let quiet = now.duration_since(self.last_received);
+        if !authenticated && seconds(config.auth_timeout).is_some_and(|timeout| now.duration_since(self.connected_at) >= timeout) {
+            return Liveness::Close("Authentication timed out");
+        }
+        if seconds(config.idle_timeout).is_some_and(|timeout| quiet >= timeout) {
+            return Liveness::Close("Idle timeout");
+        }
+
+        let Some(interval) = seconds(config.heartbeat_interval).filter(|_| sends_events(protocol_version)) else {
+            return Liveness::Alive;
+        };
+        if seconds(config.heartbeat_timeout).is_some_and(|timeout| quiet >= timeout) {
+            return Liveness::Close("No response to heartbeat");
+        }
+        let ping_due = self.last_ping.is_none_or(|last_ping| now.duration_since(last_ping) >= interval);
+        if quiet >= interval && ping_due {
+            self.last_ping = Some(now);
+            return Liveness::Ping;
+        }
+        Liveness::Alive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat() {
+        let config = ConnectionConfig {
+            heartbeat_interval: 30,
+            heartbeat_timeout: 90,
+            idle_timeout: 0,
+            auth_timeout: 60,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        let mut heartbeat = Heartbeat::new(start);
+        assert_eq!(heartbeat.check(&config, false, 2, at(10)), Liveness::Alive);
+        assert_eq!(heartbeat.check(&config, false, 2, at(60)), Liveness::Close("Authentication timed out"));
+
+        let mut heartbeat = Heartbeat::new(start);
+        assert_eq!(heartbeat.check(&config, true, 2, at(30)), Liveness::Ping);
+        assert_eq!(heartbeat.check(&config, true, 2, at(31)), Liveness::Alive);
+        assert_eq!(heartbeat.check(&config, true, 2, at(60)), Liveness::Ping);
+        heartbeat.received(at(61));
+        assert_eq!(heartbeat.check(&config, true, 2, at(80)), Liveness::Alive);
+        assert_eq!(heartbeat.check(&config, true, 2, at(151)), Liveness::Close("No response to heartbeat"));
+
+        // protocol 1 clients are never pinged, only the idle timeout applies to them
+        let mut heartbeat = Heartbeat::new(start);
+        assert_eq!(heartbeat.check(&config, true, 1, at(1000)), Liveness::Alive);
+        let idle = ConnectionConfig { idle_timeout: 300, ..config };
+        assert_eq!(heartbeat.check(&idle, true, 1, at(300)), Liveness::Close("Idle timeout"));
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
+use crate::commands::get_commands;
+use crate::config::{Config, ConnectionConfig, ListenerConfig};
+use crate::conn_handler::handle_connection;
+use crate::handoff::InheritedListeners;
+use crate::proxy_protocol;
+use crate::state::{self, Client, ClientStream, ListenerOptions, PeerAddr};
+
+// time a new connection gets to send its PROXY header and finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const BACKLOG: i32 = 1024;
+
+enum ListenSocket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+// what a connection needs from its listener, kept apart so open connections don't keep the socket alive
+struct ConnectionSetup {
+    options: Arc<ListenerOptions>,
+    tls: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+}
+
+/// A bound chat listener.
+pub struct Listener {
+    socket: ListenSocket,
+    setup: Arc<ConnectionSetup>,
+    keepalive: Option<TcpKeepalive>,
+}
+
+impl Listener {
+    /// Binds the listener, or takes over the socket a previous process passed on under the same name.
+    pub fn bind(config: &ListenerConfig, connection: &ConnectionConfig, inherited: &mut InheritedListeners) -> io::Result<Listener> {
+        let socket = match config.address.strip_prefix("unix:") {
+            Some(path) => bind_unix(&config.name, path, inherited)?,
+            None => {
+                let listener = match inherited.take_tcp(&config.name) {
+                    Some(listener) => listener,
+                    None => bind_tcp(&config.address, config.ipv6_only)?,
+                };
+                ListenSocket::Tcp(TcpListener::from_std(listener)?)
+            }
+        };
+        let tls = if config.tls {
+            Some(load_tls(&config.tls_cert, &config.tls_key)?)
+        } else {
+            None
+        };
+
+        let options = ListenerOptions {
+            name: config.name.clone(),
+            tls: config.tls,
+            require_password: config.require_password.unwrap_or_default(),
+            protocol_version: config.protocol_version,
+        };
+        let setup = ConnectionSetup { options: Arc::new(options), tls, proxy_protocol: config.proxy_protocol };
+        Ok(Listener { socket, setup: Arc::new(setup), keepalive: tcp_keepalive(connection) })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.setup.options.name
+    }
+
+    /// The bound address, for logging.
+    pub fn local_addr(&self) -> String {
+        match &self.socket {
+            ListenSocket::Tcp(listener) => listener.local_addr().map_or_else(|e| e.to_string(), |addr| addr.to_string()),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener) => listener.local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| format!("unix:{}", path.display())))
+                .unwrap_or_else(|| "unix".to_string()),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<(Box<dyn ClientStream>, PeerAddr)> {
+        match &self.socket {
+            ListenSocket::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                if let Some(keepalive) = &self.keepalive {
+                    if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(keepalive) {
+                        tracing::warn!(target: "tcpserver", "Failed to enable TCP keepalive for {}: {}", addr, e);
+                    }
+                }
+                // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                Ok((Box::new(stream), PeerAddr::Ip(addr)))
+            }
+            #[cfg(unix)]
+            ListenSocket::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Unix))
+            }
+        }
+    }
+}
+
+impl ConnectionSetup {
+    async fn handshake(&self, mut stream: Box<dyn ClientStream>, mut peer_addr: PeerAddr) -> io::Result<(Box<dyn ClientStream>, PeerAddr)> {
+        if self.proxy_protocol {
+            if let Some(addr) = proxy_protocol::read_header(&mut stream).await? {
+                peer_addr = PeerAddr::Ip(addr);
+            }
+        }
+        if let Some(tls) = &self.tls {
+            stream = Box::new(tls.accept(stream).await?);
+        }
+        Ok((stream, peer_addr))
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Listener {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        match &self.socket {
+            ListenSocket::Tcp(listener) => listener.as_raw_fd(),
+            ListenSocket::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsRawSocket for Listener {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        match &self.socket {
+            ListenSocket::Tcp(listener) => listener.as_raw_socket(),
+        }
+    }
+}
+
+// lets the system notice peers that vanished without closing the connection
+fn tcp_keepalive(config: &ConnectionConfig) -> Option<TcpKeepalive> {
+    if config.tcp_keepalive == 0 {
+        return None;
+    }
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(config.tcp_keepalive))
+        .with_interval(Duration::from_secs(config.tcp_keepalive_interval.max(1)));
+    #[cfg(unix)]
+    let keepalive = keepalive.with_retries(config.tcp_keepalive_retries.max(1));
+    Some(keepalive)
+}
+
+// IPv6 sockets are dual-stack unless ipv6_only is set, whatever the system default is
+fn bind_tcp(address: &str, ipv6_only: bool) -> io::Result<std::net::TcpListener> {
+    let addr = address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} did not resolve to an address", address)))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(name: &str, path: &str, inherited: &mut InheritedListeners) -> io::Result<ListenSocket> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let listener = match inherited.take_unix(name) {
+        Some(listener) => listener,
+        None => {
+            // a socket file left behind by a server that did not shut down cleanly makes bind fail,
+            // one that still accepts connections belongs to a running server and is left alone
+            let stale = std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+                && std::os::unix::net::UnixStream::connect(path).is_err();
+            if stale {
+                std::fs::remove_file(path)?;
+            }
+            let listener = std::os::unix::net::UnixListener::bind(path)?;
+            listener.set_nonblocking(true)?;
+            listener
+        }
+    };
+    Ok(ListenSocket::Unix(tokio::net::UnixListener::from_std(listener)?))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_name: &str, _path: &str, _inherited: &mut InheritedListeners) -> io::Result<ListenSocket> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are only supported on unix"))
+}
+
+fn load_tls(cert_path: &str, key_path: &str) -> io::Result<TlsAcceptor> {
+    let with_path = |path: &str, e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+    let certs = File::open(cert_path)
+        .and_then(|file| rustls_pemfile::certs(&mut BufReader::new(file)).collect::<io::Result<Vec<_>>>())
+        .map_err(|e| with_path(cert_path, e))?;
+    let key = File::open(key_path)
+        .and_then(|file| rustls_pemfile::private_key(&mut BufReader::new(file)))
+        .map_err(|e| with_path(key_path, e))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{}: no private key found", key_path)))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts connections until the task is aborted, each one is handled on its own task.
+pub async fn serve(listener: Arc<Listener>, config: Config) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // usually out of file descriptors, give connections a moment to close
+                tracing::error!(target: "tcpserver", "Failed to accept a connection on {}: {}", listener.name(), e);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let setup = listener.setup.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let (stream, peer_addr) = match timeout(HANDSHAKE_TIMEOUT, setup.handshake(stream, peer_addr)).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(e)) => {
+                    tracing::warn!(target: "tcpserver", "Dropped connection from {} on {}: {}", peer_addr, setup.options.name, e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::warn!(target: "tcpserver", "Dropped connection from {} on {}: handshake timed out", peer_addr, setup.options.name);
+                    return;
+                }
+            };
+
+            let connection_id = state::next_connection_id();
+            let span = tracing::info_span!("connection", conn_id = connection_id, peer = %peer_addr, listener = %setup.options.name, username = tracing::field::Empty);
+            span.in_scope(|| tracing::info!(target: "tcpserver", "New connection accepted"));
+
+            let client = Arc::new(Client::new(connection_id, peer_addr, setup.options.clone(), stream));
+            state::get_active_connections().write().await.push(client.clone());
+            handle_connection(client, config, get_commands()).instrument(span).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_dual_stack_and_unix_listeners() {
+        let mut inherited = InheritedListeners::default();
+        let config = ListenerConfig { name: "v6".to_string(), address: "[::]:0".to_string(), ..Default::default() };
+        // not every sandbox has IPv6
+        if let Ok(listener) = Listener::bind(&config, &ConnectionConfig::default(), &mut inherited) {
+            let port = listener.local_addr().rsplit(':').next().unwrap().to_string();
+            let connect = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port));
+            let (accepted, _) = tokio::join!(listener.accept(), connect);
+            let (_, peer_addr) = accepted.unwrap();
+            assert!(matches!(peer_addr, PeerAddr::Ip(addr) if addr.ip().to_canonical().is_loopback()));
+        }
+
+        let path = std::env::temp_dir().join(format!("netchat-test-{}.sock", std::process::id()));
+        let config = ListenerConfig {
+            name: "local".to_string(),
+            address: format!("unix:{}", path.display()),
+            proxy_protocol: true,
+            ..Default::default()
+        };
+        let listener = Listener::bind(&config, &ConnectionConfig::default(), &mut inherited).unwrap();
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"PROXY TCP4 203.0.113.9 10.0.0.1 4000 2052\r\nHELLO").await.unwrap();
+
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr, PeerAddr::Unix);
+        let (mut stream, peer_addr) = listener.setup.handshake(stream, peer_addr).await.unwrap();
+        assert_eq!(peer_addr.to_string(), "203.0.113.9:4000");
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"HELLO");
+
+        // a second bind over the socket file of a live listener fails instead of stealing it
+        assert!(Listener::bind(&config, &ConnectionConfig::default(), &mut inherited).is_err());
+        drop(listener);
+        assert!(Listener::bind(&config, &ConnectionConfig::default(), &mut inherited).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+use crate::config::{LogFormat, LogRotation, LoggingConfig};
+
+static REDACT: AtomicBool = AtomicBool::new(false);
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Sets up the global tracing subscriber from the logging config.
+/// The returned guard flushes the log file on drop, keep it alive until the server exits.
+pub fn init_logging(config: &LoggingConfig) -> Option<WorkerGuard> {
+    REDACT.store(config.redact, Ordering::Relaxed);
+
+    let mut layers: Vec<BoxedLayer> = vec![format_layer(config.format, std::io::stdout, true)];
+    let mut guard = None;
+    let mut file_error = None;
+
+    if !config.directory.is_empty() {
+        match file_appender(config) {
+            Ok(appender) => {
+                let (writer, worker_guard) = tracing_appender::non_blocking(appender);
+                layers.push(format_layer(config.format, writer, false));
+                guard = Some(worker_guard);
+            }
+            Err(e) => file_error = Some(e),
+        }
+    }
+
+    let filter = build_filter(config);
+    tracing_subscriber::registry()
+        .with(layers.with_filter(filter))
+        .init();
+
+    if let Some(e) = file_error {
+        tracing::error!(target: "logging", "Failed to open log directory {}: {}", config.directory, e);
+    }
+    guard
+}
+
+fn build_filter(config: &LoggingConfig) -> EnvFilter {
+    let directives = if config.filter.is_empty() {
+        config.level.clone()
+    } else {
+        format!("{},{}", config.level, config.filter)
+    };
+    EnvFilter::try_new(&directives).unwrap_or_else(|e| {
+        eprintln!("Invalid logging filter \"{}\": {}, falling back to info", directives, e);
+        EnvFilter::new("info")
+    })
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Pretty => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer().json().with_current_span(true).with_span_list(false).with_writer(writer).boxed(),
+    }
+}
+
+fn file_appender(config: &LoggingConfig) -> Result<RollingFileAppender, Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(&config.directory)?;
+    let rotation = match config.rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix("netchat")
+        .filename_suffix("log");
+    if config.max_files > 0 {
+        builder = builder.max_log_files(config.max_files);
+    }
+    Ok(builder.build(&config.directory)?)
+}
+
+/// Hides message bodies and tokens from logs when `logging.redact` is enabled.
+pub fn redact(text: &str) -> &str {
+    if REDACT.load(Ordering::Relaxed) {
+        "[redacted]"
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_filter() {
+        let mut config = LoggingConfig::default();
+        assert_eq!(build_filter(&config).to_string(), "info");
+
+        config.filter = "netchat_server=debug".to_string();
+        assert!(build_filter(&config).to_string().contains("netchat_server=debug"));
+
+        config.level = "not a level!".to_string();
+        assert_eq!(build_filter(&config).to_string(), "info");
+    }
+}
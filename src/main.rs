@@ -1,12 +1,7 @@
-use tokio::net::TcpListener;
 use std::error::Error;
-use tracing_subscriber::fmt;
 use std::sync::Arc;
-use tokio::signal;
-use tokio::io::AsyncWriteExt;
-use tokio::time::{sleep, Duration};
-use std::fs;
-use reqwest;
+use tokio::time::Duration;
+use clap::Parser;
 
 mod config;
 mod auth;
@@ -17,14 +12,35 @@ mod validators;
 mod commands;
 mod state;
 mod textutils;
-
-use config::Config;
-use conn_handler::handle_connection;
+mod metrics;
+mod logging;
+mod shutdown;
+mod handoff;
+mod resume;
+mod web_auth;
+mod admin;
+mod events;
+mod archive;
+mod stats;
+mod profiles;
+mod cli;
+mod listeners;
+mod proxy_protocol;
+mod heartbeat;
+mod activity;
+mod blocks;
+mod mentions;
+mod reactions;
+mod rooms;
+mod threads;
+mod topics;
+mod welcome;
+
+use cli::Cli;
+use config::{Config, ConfigSource};
 use db::init_db;
-use crate::commands::get_commands;
-use crate::state::get_active_users;
+use crate::shutdown::DrainReason;
 
-const CONFIG_URL: &str = "https://raw.githubusercontent.com/tkbstudios/netchat-server-rust/master/config.toml.example";
 const CONFIG_PATH: &str = if cfg!(test) {
     "config-test.toml"
 } else {
@@ -36,40 +52,65 @@ const DB_PATH: &str = if cfg!(test) {
     "netchat.db"
 };
 
-fn init_tracing() {
-    fmt::init();
-}
-
-async fn fetch_and_save_config() -> Result<(), Box<dyn Error + Send + Sync>> {
-    if !DB_PATH.ends_with(".db") {
-        return Err("DB path must end with .db".into());
-    }
-    let response = reqwest::get(CONFIG_URL).await?;
-    let content = response.text().await?;
-    fs::write(CONFIG_PATH, content)?;
-    Ok(())
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    init_tracing();
+    lazy_static::initialize(&state::SERVER_START_TIME);
 
-    if !std::path::Path::new(CONFIG_PATH).exists() {
-        tracing::warn!("Config file not found, fetching from remote...");
-        fetch_and_save_config().await.expect("Failed to fetch config file");
-        tracing::info!("Config file fetched and saved as config.toml. Please edit it carefully before restarting the server.");
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        if let Err(e) = cli::run(command) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
         return Ok(());
     }
 
-    let config = Config::load_config().expect("Failed to load config");
+    let (config, config_source) = match Config::load_config(cli.config.as_deref()) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(problems) = config.validate() {
+        eprintln!("Invalid config from {}:\n{}", config_source, problems);
+        std::process::exit(1);
+    }
+    if cli.check_config {
+        println!("# effective config from {}, with environment overrides applied", config_source);
+        print!("{}", toml::to_string_pretty(&config.redacted())?);
+        return Ok(());
+    }
+
+    let _log_guard = logging::init_logging(&config.logging);
+    if matches!(config_source, ConfigSource::BuiltIn) {
+        tracing::warn!("No config file found, running on the built-in defaults. Create config.toml or set NETCHAT_* variables to change them");
+    } else {
+        tracing::info!("Loaded config from {}", config_source);
+    }
     let config_clone_for_web = config.clone();
 
     init_db().expect("Failed to initialize database");
-
-    tracing::info!(target: "tcpserver", "Starting server with online mode: {} on {}:{}", config.server.online_mode, config.server.host, config.server.port);
-
-    let listener = TcpListener::bind(format!("{}:{}", config.server.host, config.server.port)).await?;
-    let active_connections = state::get_active_connections();
+    welcome::init(&config.welcome);
+
+    tracing::info!(target: "tcpserver", "Starting server with online mode: {}", config.server.online_mode);
+
+    let mut inherited_listeners = handoff::inherited_listeners();
+    let mut chat_listeners = Vec::new();
+    for listener_config in config.chat_listeners() {
+        let listener = listeners::Listener::bind(&listener_config, &config.connection, &mut inherited_listeners)
+            .map_err(|e| format!("Failed to listen on {} ({}): {}", listener_config.address, listener_config.name, e))?;
+        tracing::info!(
+            target: "tcpserver",
+            "Listening on {} ({}, tls: {}, password: {}, protocol {})",
+            listener.local_addr(),
+            listener.name(),
+            listener_config.tls,
+            listener_config.require_password.unwrap_or_default(),
+            listener_config.protocol_version
+        );
+        chat_listeners.push(Arc::new(listener));
+    }
     let chat_rooms = state::get_chat_rooms();
 
     {
@@ -77,82 +118,77 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         chat_rooms.insert("global".to_string(), Vec::new());
     }
 
+    let web_handle = axum_server::Handle::new();
+    // kept around to pass the web port on to the next process on restart
+    let mut web_listener_for_handoff = None;
     if config.web.enable {
+        let web_listener = match inherited_listeners.take_tcp("web") {
+            Some(listener) => listener,
+            None => web_ui::bind_web_listener(&config)?,
+        };
+        web_listener_for_handoff = Some(web_listener.try_clone()?);
+        let web_handle = web_handle.clone();
         tokio::spawn(async move {
-            web_ui::run_web_ui(config_clone_for_web).await;
+            web_ui::run_web_ui(config_clone_for_web, web_listener, web_handle).await;
         });
     }
 
-    tokio::spawn(remove_non_authenticated_connections());
+    let accept_tasks: Vec<_> = chat_listeners.iter()
+        .map(|listener| tokio::spawn(listeners::serve(listener.clone(), config.clone())))
+        .collect();
+    tokio::spawn(profiles::refresh_online_profiles(config.clone()));
+
+    let shutdown_signal = shutdown::wait_for_signal();
+    tokio::pin!(shutdown_signal);
+    let restart_signal = handoff::wait_for_restart_signal();
+    tokio::pin!(restart_signal);
+    let mut drain_reason = DrainReason::Shutdown;
 
     loop {
         tokio::select! {
-            Ok((socket, _)) = listener.accept() => {
-                tracing::info!(target: "tcpserver", "New connection accepted");
-
-                let socket = Arc::new(tokio::sync::Mutex::new(socket));
-                let commands_clone = get_commands();
-
-                {
-                    let mut conns = active_connections.write().await;
-                    conns.push(socket.clone());
-                }
-
-                let config_clone = config.clone();
-                tokio::spawn(handle_connection(
-                    socket,
-                    config_clone,
-                    commands_clone
-                ));
+            _ = &mut shutdown_signal => {
+                tracing::info!("Shutdown signal received, no longer accepting connections");
+                break;
             },
 
-            _ = signal::ctrl_c() => {
-                tracing::info!("Shutdown signal received, notifying all clients...");
-
-                let active_connections = state::get_active_connections();
-                let conns = active_connections.read().await;
-                for conn in conns.iter() {
-                    let conn = conn.clone();
-                    tokio::spawn(async move {
-                        let mut conn = conn.lock().await;
-                        let _ = conn.write_all(b"SERVER_SHUTDOWN\n").await;
-                    });
+            _ = &mut restart_signal => {
+                tracing::info!("Restart signal received, handing listeners over to a new process");
+                let mut sockets: Vec<(&str, &dyn handoff::HandoffSocket)> = chat_listeners.iter()
+                    .map(|listener| (listener.name(), listener.as_ref() as &dyn handoff::HandoffSocket))
+                    .collect();
+                if let Some(web_listener) = &web_listener_for_handoff {
+                    sockets.push(("web", web_listener));
+                }
+                match handoff::spawn_successor(&sockets).await {
+                    Ok(()) => {
+                        drain_reason = DrainReason::Restart;
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::error!("Restart failed, keeping this process running: {}", e);
+                        restart_signal.set(handoff::wait_for_restart_signal());
+                    }
                 }
-
-                sleep(Duration::from_secs(5)).await;
-
-                tracing::info!("Server shutting down after 5 seconds.");
-                break;
             },
         }
     }
 
-    Ok(())
-}
-
-async fn remove_non_authenticated_connections() {
-    loop {
-        sleep(Duration::from_secs(60)).await;
-        let mut connections_to_remove = Vec::new();
-        let active_users = get_active_users();
-        let active_connections = state::get_active_connections();
-        {
-            let active_users = active_users.read().await;
-            let active_connections = active_connections.read().await;
-            for (index, conn) in active_connections.iter().enumerate() {
-                if !active_users.values().any(|user_conn| Arc::ptr_eq(user_conn, conn)) {
-                    connections_to_remove.push(index);
-                }
-            }
-        }
-        for index in connections_to_remove.iter().rev() {
-            let active_connections = state::get_active_connections();
-            let mut conns = active_connections.write().await;
-            let conn = conns.remove(*index);
-            {
-                let mut conn = conn.lock().await;
-                let _ = conn.shutdown().await;
-            }
-        }
+    for task in accept_tasks {
+        task.abort();
+        let _ = task.await;
+    }
+    drop(chat_listeners);
+    drop(web_listener_for_handoff);
+    let web_drain_timeout = Some(Duration::from_secs(config.shutdown.drain_timeout));
+    if drain_reason == DrainReason::Restart {
+        // the new process is serving the web UI on the same socket already
+        web_handle.graceful_shutdown(web_drain_timeout);
+        shutdown::drain_connections(&config.shutdown, drain_reason).await;
+    } else {
+        shutdown::drain_connections(&config.shutdown, drain_reason).await;
+        web_handle.graceful_shutdown(web_drain_timeout);
     }
+    tracing::info!("Server shut down");
+
+    Ok(())
 }
@@ -0,0 +1,110 @@
+use crate::blocks::blockers_of;
+use crate::conn_handler::{send_to_clients, write_to_socket};
+use crate::db::{add_mention, get_user, take_unnotified_mentions, StoredMessage};
+use crate::state::{sends_events, sessions_of, ClientSocket};
+use crate::textutils::format_outgoing_message;
+use crate::validators::validate_username;
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Everything written as `@name` in `text`, in order. An `@` inside a word, like in an email address, is no mention.
+pub fn parse_mentions(text: &str) -> Vec<&str> {
+    let mut mentions = Vec::new();
+    let mut previous = None;
+    for (index, c) in text.char_indices() {
+        if c == '@' && !previous.is_some_and(is_username_char) {
+            let rest = &text[index + 1..];
+            let end = rest.find(|c: char| !is_username_char(c)).unwrap_or(rest.len());
+            if end > 0 {
+                mentions.push(&rest[..end]);
+            }
+        }
+        previous = Some(c);
+    }
+    mentions
+}
+
+// "@bob." at the end of a sentence means bob, unless there really is a user called "bob."
+fn resolve_user(mention: &str) -> Option<String> {
+    [mention, mention.trim_end_matches(['.', '-'])]
+        .into_iter()
+        .filter(|name| validate_username(name))
+        .find_map(|name| get_user(name).ok().flatten())
+        .map(|user| user.username)
+}
+
+fn mention_frame(message: &StoredMessage) -> String {
+    let line = format_outgoing_message(&message.sender, &message.recipient, &message.message, message.timestamp);
+    format!("MENTION:{}:{}\n", message.id, line)
+}
+
+/// Stores the mentions in `message` and sends `MENTION` frames to the mentioned users that are online.
+/// Users without a protocol 2 connection get theirs when they next log in with one.
+pub async fn notify(message: &StoredMessage) {
+    let mut mentioned: Vec<String> = parse_mentions(&message.message).into_iter().filter_map(resolve_user).collect();
+    mentioned.sort();
+    mentioned.dedup();
+
+    let blockers = blockers_of(&message.sender);
+    for username in mentioned.into_iter().filter(|username| *username != message.sender && !blockers.contains(username)) {
+        let mut sessions = sessions_of(&username).await;
+        sessions.retain(|client| client.listener.sends_events());
+        send_to_clients(&sessions, &mention_frame(message));
+        if let Err(e) = add_mention(message.id, &username, !sessions.is_empty()) {
+            tracing::error!(target: "db", "Failed to store mention of {}: {}", username, e);
+        }
+    }
+}
+
+/// Sends the mentions `username` missed to a freshly logged in connection.
+pub async fn send_missed(username: &str, protocol_version: u8, socket: &mut ClientSocket<'_>) {
+    if !sends_events(protocol_version) {
+        return;
+    }
+    match take_unnotified_mentions(username) {
+        Ok(messages) => {
+            for message in &messages {
+                let _ = write_to_socket(socket, mention_frame(message).as_bytes()).await;
+            }
+        }
+        Err(e) => tracing::error!(target: "db", "Failed to load missed mentions of {}: {}", username, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_message_to_db, get_mentions, seed_test_users};
+
+    #[test]
+    fn test_parse_mentions() {
+        assert_eq!(parse_mentions("@alice and @bob.smith, see @carol."), vec!["alice", "bob.smith", "carol."]);
+        assert_eq!(parse_mentions("mail me@example.com or @ nobody"), Vec::<&str>::new());
+        assert_eq!(parse_mentions("(@dave)"), vec!["dave"]);
+    }
+
+    #[tokio::test]
+    async fn test_offline_mentions_are_kept() {
+        seed_test_users(&["alice", "bob"]);
+        let text = "@bob. @bob @nobody @alice";
+        let id = add_message_to_db(100, "alice", "global", text, None).unwrap();
+        let message = StoredMessage {
+            id,
+            timestamp: 100,
+            sender: "alice".to_string(),
+            recipient: "global".to_string(),
+            message: text.to_string(),
+            parent_id: None,
+        };
+        notify(&message).await;
+
+        // mentioned once, and not of herself
+        assert_eq!(get_mentions("bob", 10).unwrap().len(), 1);
+        assert!(get_mentions("alice", 10).unwrap().is_empty());
+        assert_eq!(take_unnotified_mentions("bob").unwrap()[0].id, id);
+        assert!(take_unnotified_mentions("bob").unwrap().is_empty());
+        assert_eq!(get_mentions("bob", 10).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Gauge, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, TextEncoder,
+};
+use sysinfo::{Pid, System};
+use crate::rooms::is_room;
+use crate::state::{get_active_connections, get_active_users, get_uptime, SERVER_START_TIME};
+
+lazy_static! {
+    pub static ref CONNECTIONS_GAUGE: IntGauge = register_int_gauge!(
+        "netchat_active_connections",
+        "Number of open client connections"
+    ).unwrap();
+    pub static ref AUTHENTICATED_USERS_GAUGE: IntGauge = register_int_gauge!(
+        "netchat_authenticated_users",
+        "Number of authenticated users currently online"
+    ).unwrap();
+    pub static ref MESSAGES_SENT: IntCounterVec = register_int_counter_vec!(
+        "netchat_messages_total",
+        "Chat messages sent, by recipient type",
+        &["recipient_type"]
+    ).unwrap();
+    pub static ref AUTH_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "netchat_auth_attempts_total",
+        "Authentication attempts, by result",
+        &["result"]
+    ).unwrap();
+    pub static ref COMMAND_INVOCATIONS: IntCounterVec = register_int_counter_vec!(
+        "netchat_command_invocations_total",
+        "Chat command invocations, by command",
+        &["command"]
+    ).unwrap();
+    pub static ref COMMAND_DURATION: HistogramVec = register_histogram_vec!(
+        "netchat_command_duration_seconds",
+        "Chat command execution time, by command",
+        &["command"]
+    ).unwrap();
+    pub static ref DB_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "netchat_db_query_duration_seconds",
+        "Database query time, by query",
+        &["query"],
+        vec![0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    ).unwrap();
+    pub static ref BYTES_RECEIVED: IntCounter = register_int_counter!(
+        "netchat_bytes_received_total",
+        "Bytes read from client sockets"
+    ).unwrap();
+    pub static ref BYTES_SENT: IntCounter = register_int_counter!(
+        "netchat_bytes_sent_total",
+        "Bytes written to client sockets"
+    ).unwrap();
+    static ref PROCESS_CPU_USAGE: Gauge = register_gauge!(
+        "netchat_process_cpu_usage_percent",
+        "CPU usage of the server process since the previous sample"
+    ).unwrap();
+    static ref PROCESS_MEMORY: IntGauge = register_int_gauge!(
+        "netchat_process_resident_memory_bytes",
+        "Resident memory of the server process"
+    ).unwrap();
+    static ref UPTIME: IntGauge = register_int_gauge!(
+        "netchat_uptime_seconds",
+        "Seconds since the server started"
+    ).unwrap();
+    static ref START_TIME: IntGauge = register_int_gauge!(
+        "netchat_start_time_seconds",
+        "Unix timestamp at which the server started"
+    ).unwrap();
+    // /metrics keeps its own sampler so scrapes and ?perf don't cut each other's CPU window short
+    static ref SAMPLER: ProcessSampler = ProcessSampler::default();
+}
+
+pub struct ProcessUsage {
+    pub cpu_usage: f32,
+    pub memory: u64,
+    pub total_memory: u64,
+}
+
+/// Samples CPU and memory usage of the server process.
+/// CPU usage is measured since the previous sample of the same sampler, so the first sample reads 0.
+#[derive(Default)]
+pub struct ProcessSampler {
+    system: Mutex<System>,
+}
+
+impl ProcessSampler {
+    pub fn sample(&self) -> ProcessUsage {
+        let mut system = self.system.lock().unwrap();
+        system.refresh_memory();
+
+        let (cpu_usage, memory) = match sysinfo::get_current_pid() {
+            Ok(pid) => process_stats(&mut system, pid),
+            Err(_) => (0.0, 0),
+        };
+
+        ProcessUsage {
+            cpu_usage,
+            memory,
+            total_memory: system.total_memory(),
+        }
+    }
+}
+
+fn process_stats(system: &mut System, pid: Pid) -> (f32, u64) {
+    if !system.refresh_process(pid) {
+        return (0.0, 0);
+    }
+    system.process(pid)
+        .map(|process| (process.cpu_usage(), process.memory()))
+        .unwrap_or((0.0, 0))
+}
+
+/// Starts a timer that records into the db query histogram when dropped.
+pub fn db_timer(query: &str) -> HistogramTimer {
+    DB_QUERY_DURATION.with_label_values(&[query]).start_timer()
+}
+
+pub fn recipient_type(recipient: &str) -> &'static str {
+    if recipient == "global" {
+        "global"
+    } else if is_room(recipient) {
+        "room"
+    } else {
+        "direct"
+    }
+}
+
+/// Refreshes the sampled gauges and renders every metric in the Prometheus text format.
+pub async fn render_metrics() -> String {
+    CONNECTIONS_GAUGE.set(get_active_connections().read().await.len() as i64);
+    AUTHENTICATED_USERS_GAUGE.set(get_active_users().read().await.len() as i64);
+    UPTIME.set(get_uptime());
+    START_TIME.set(*SERVER_START_TIME);
+
+    let usage = SAMPLER.sample();
+    PROCESS_CPU_USAGE.set(usage.cpu_usage as f64);
+    PROCESS_MEMORY.set(usage.memory as i64);
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!(target: "metrics", "Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{seed_test_room, seed_test_users};
+
+    #[test]
+    fn test_render_metrics() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        AUTH_ATTEMPTS.with_label_values(&["success"]).inc();
+        BYTES_SENT.inc_by(12);
+        let output = rt.block_on(render_metrics());
+
+        assert!(output.contains("netchat_active_connections"));
+        assert!(output.contains("netchat_auth_attempts_total{result=\"success\"}"));
+        assert!(output.contains("netchat_bytes_sent_total"));
+        assert!(output.contains("netchat_uptime_seconds"));
+        assert!(output.contains("netchat_process_resident_memory_bytes"));
+    }
+
+    #[test]
+    fn test_recipient_type() {
+        seed_test_users(&["testuser"]);
+        seed_test_room("#lobby", "testuser");
+        assert_eq!(recipient_type("global"), "global");
+        assert_eq!(recipient_type("#lobby"), "room");
+        assert_eq!(recipient_type("testuser"), "direct");
+    }
+}
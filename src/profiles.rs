@@ -0,0 +1,135 @@
+use axum::{
+    extract::{Json, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::time::{sleep, Duration};
+use crate::auth::verifier;
+use crate::config::Config;
+use crate::db::{get_user, update_user_profile, UserProfile};
+use crate::state::get_active_users;
+use crate::validators::validate_username;
+use crate::web_auth::error_response;
+
+// the refresh loop never wakes up more often than this
+const MIN_REFRESH_INTERVAL: u64 = 60;
+
+#[derive(Serialize)]
+struct Whois<'a> {
+    username: &'a str,
+    status: &'a str,
+    permission: &'a str,
+    #[serde(flatten)]
+    profile: &'a UserProfile,
+}
+
+fn profiles_enabled(config: &Config) -> bool {
+    config.server.online_mode && !config.tinet.profile_url.is_empty()
+}
+
+/// Pulls the TINET profile of `username` into the users table, unless it was synced less than `profile_refresh` ago.
+pub async fn sync_profile(config: &Config, username: &str) {
+    if !profiles_enabled(config) {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    match get_user(username) {
+        Ok(Some(user)) if user.profile_synced_at.is_some_and(|synced_at| now - synced_at < config.tinet.profile_refresh as i64) => return,
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to load user {}: {}", username, e);
+            return;
+        }
+    }
+
+    match verifier(config).fetch_profile(username).await {
+        Ok(Some(profile)) => {
+            if let Err(e) = update_user_profile(username, &profile, now) {
+                tracing::error!(target: "db", "Failed to store profile of {}: {}", username, e);
+            } else {
+                tracing::debug!(target: "auth", "Synced TINET profile of {}", username);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!(target: "auth", "Failed to fetch TINET profile of {}: {}", username, e),
+    }
+}
+
+/// Keeps the profiles of online users up to date while the server runs.
+pub async fn refresh_online_profiles(config: Config) {
+    if !profiles_enabled(&config) {
+        return;
+    }
+    loop {
+        sleep(Duration::from_secs(config.tinet.profile_refresh.max(MIN_REFRESH_INTERVAL))).await;
+        let usernames: Vec<String> = get_active_users().read().await.keys().cloned().collect();
+        for username in usernames {
+            sync_profile(&config, &username).await;
+        }
+    }
+}
+
+/// The answer to `WHOIS:<user>`, `WHOIS:<user>:<json>` or `WHOIS_NOT_FOUND:<user>`.
+pub fn whois(username: &str) -> String {
+    let user = if validate_username(username) {
+        get_user(username).unwrap_or_else(|e| {
+            tracing::error!(target: "db", "Failed to load user {}: {}", username, e);
+            None
+        })
+    } else {
+        None
+    };
+    let Some(user) = user else {
+        return format!("WHOIS_NOT_FOUND:{}\n", username);
+    };
+
+    let whois = Whois {
+        username: &user.username,
+        status: &user.status,
+        permission: &user.permission,
+        profile: &user.profile,
+    };
+    format!("WHOIS:{}:{}\n", user.username, serde_json::to_string(&whois).unwrap_or_default())
+}
+
+/// `/api/users/:username`, a single user with the profile cached from TINET.
+pub async fn user_handler(Path(username): Path<String>) -> Response {
+    match get_user(&username) {
+        Ok(Some(user)) => Json(user).into_response(),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "no such user"),
+        Err(e) => {
+            tracing::error!(target: "webserver", "Failed to load user {}: {}", username, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::seed_test_users;
+
+    #[test]
+    fn test_whois() {
+        seed_test_users(&["alice"]);
+        let profile = UserProfile {
+            display_name: Some("Alice".to_string()),
+            calculator: None,
+            flags: vec!["staff".to_string()],
+        };
+        update_user_profile("alice", &profile, 1000).unwrap();
+
+        let frame = whois("alice");
+        let json = frame.strip_prefix("WHOIS:alice:").unwrap();
+        let json: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(json["display_name"], "Alice");
+        assert_eq!(json["calculator"], serde_json::Value::Null);
+        assert_eq!(json["flags"], serde_json::json!(["staff"]));
+        assert_eq!(json["permission"], "user");
+
+        assert_eq!(whois("nobody"), "WHOIS_NOT_FOUND:nobody\n");
+        assert_eq!(whois("not a name!"), "WHOIS_NOT_FOUND:not a name!\n");
+    }
+}
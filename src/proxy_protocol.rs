@@ -0,0 +1,115 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// the longest possible v1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PROXY protocol header: {}", message))
+}
+
+/// Reads a PROXY protocol v1 or v2 header off the start of `stream`, and nothing past it.
+/// Returns the original client address, or None for connections the proxy made itself (LOCAL, UNKNOWN).
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        let mut addresses = vec![0u8; u16::from_be_bytes([head[2], head[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(head[0], head[1], &addresses);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing"));
+    }
+
+    // v1 is a single line, read byte by byte so none of the client's data is consumed
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("line too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("not text"))?;
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad source address"))?;
+            let port: u16 = source_port.parse().map_err(|_| invalid("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("unknown v1 format")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, a health check from the proxy itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+
+    match family {
+        // TCP over IPv4: source, destination, source port, destination port
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]]))))
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([addresses[32], addresses[33]]))))
+        }
+        0x11 | 0x21 => Err(invalid("address block too short")),
+        // UNSPEC, unix sockets and datagrams carry no address we could use
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 2052\r\nAUTH:alice";
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"AUTH:alice");
+
+        let mut stream: &[u8] = b"PROXY TCP6 ::1 ::1 4000 2052\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("[::1]:4000".parse().unwrap()));
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 12, 10, 0, 0, 7, 10, 0, 0, 1, 0x1f, 0x90, 0x08, 0x04]);
+        v2.extend(b"AUTH:bob");
+        let mut stream = v2.as_slice();
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("10.0.0.7:8080".parse().unwrap()));
+        assert_eq!(stream, b"AUTH:bob");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+
+        assert!(read_header(&mut &b"AUTH:alice:token\n"[..]).await.is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 nonsense\r\n"[..]).await.is_err());
+        let too_long = [b"PROXY ".as_slice(), &[b'x'; 200]].concat();
+        assert!(read_header(&mut too_long.as_slice()).await.is_err());
+    }
+}
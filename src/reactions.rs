@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use chrono::Utc;
+use crate::activity::conversation_of;
+use crate::blocks::without_blockers;
+use crate::conn_handler::send_to_clients;
+use crate::db::{add_reaction, get_message, get_reaction_counts, StoredMessage};
+use crate::state::{get_chat_rooms, get_rooms_of, members_of, sessions_of, Client};
+use crate::validators::validate_reaction;
+
+/// Every connection that can see `message`: everyone for global, the members of a room, both sides of a direct message.
+pub async fn audience_of(message: &StoredMessage) -> Vec<Arc<Client>> {
+    if message.recipient == "global" || get_chat_rooms().read().await.contains_key(&message.recipient) {
+        return members_of(&message.recipient).await;
+    }
+    let mut audience = sessions_of(&message.sender).await;
+    if message.recipient != message.sender {
+        audience.extend(sessions_of(&message.recipient).await);
+    }
+    audience
+}
+
+/// Handles `REACT:<message-id>:<reaction>` and returns the reply for the reacting connection.
+/// Everyone else who can see the message and speaks protocol 2 is sent `REACTION:<message-id>:<username>:<reaction>`.
+pub async fn react(client: &Arc<Client>, username: &str, arguments: &str) -> String {
+    let (message_id, reaction) = arguments.split_once(':').unwrap_or((arguments, ""));
+    let invalid = format!("REACT_INVALID:{}\n", message_id);
+    let Ok(id) = message_id.parse::<i64>() else {
+        return invalid;
+    };
+    if !validate_reaction(reaction) {
+        return invalid;
+    }
+    let message = match get_message(id) {
+        Ok(Some(message)) => message,
+        Ok(None) => return invalid,
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to load message {}: {}", id, e);
+            return invalid;
+        }
+    };
+    if conversation_of(&message, username, &get_rooms_of(client).await).is_none() {
+        return invalid;
+    }
+
+    match add_reaction(id, username, reaction, Utc::now().timestamp()) {
+        Ok(true) => {
+            let mut audience = without_blockers(&audience_of(&message).await, username);
+            audience.retain(|other| other.listener.sends_events() && !Arc::ptr_eq(other, client));
+            send_to_clients(&audience, &format!("REACTION:{}:{}:{}\n", id, username, reaction));
+        }
+        // reacting twice with the same thing changes nothing
+        Ok(false) => {}
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to store reaction of {}: {}", username, e);
+            return invalid;
+        }
+    }
+    format!("REACTED:{}:{}\n", id, reaction)
+}
+
+/// `REACTIONS:<message-id>:<reaction>=<count>,...` for each of `messages` that has reactions, keyed by message id.
+pub fn summaries(messages: &[StoredMessage]) -> HashMap<i64, String> {
+    let ids: Vec<i64> = messages.iter().map(|message| message.id).collect();
+    let counts = get_reaction_counts(&ids).unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to load reactions: {}", e);
+        HashMap::new()
+    });
+    counts.into_iter()
+        .map(|(id, counts)| {
+            let counts: Vec<String> = counts.iter().map(|(reaction, count)| format!("{}={}", reaction, count)).collect();
+            (id, format!("REACTIONS:{}:{}\n", id, counts.join(",")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_message_to_db, seed_test_users};
+
+    #[test]
+    fn test_reaction_summaries() {
+        seed_test_users(&["alice", "bob", "carol"]);
+        let first = add_message_to_db(100, "alice", "global", "lunch?", None).unwrap();
+        let second = add_message_to_db(200, "bob", "global", "sure", None).unwrap();
+        assert!(add_reaction(first, "bob", "+1", 300).unwrap());
+        assert!(add_reaction(first, "carol", "ok", 310).unwrap());
+        assert!(add_reaction(first, "carol", "+1", 320).unwrap());
+        assert!(!add_reaction(first, "carol", "+1", 330).unwrap());
+
+        let messages = crate::db::get_messages("global", 10).unwrap();
+        let summaries = summaries(&messages);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[&first], format!("REACTIONS:{}:+1=2,ok=1\n", first));
+        assert!(!summaries.contains_key(&second));
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::Utc;
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+use crate::config::ResumeConfig;
+use crate::db::{add_resume_token, delete_resume_token, purge_expired_resume_tokens, set_resume_token_disconnected, take_resume_token};
+
+const TOKEN_LENGTH: usize = 32;
+
+/// What a resumed connection gets back from its previous session.
+pub struct ResumedSession {
+    // missed messages are the ones after this
+    pub last_message_id: i64,
+    pub rooms: Vec<String>,
+}
+
+// only a hash of each token is kept, a leaked database can't be used to take over sessions
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Creates a resume token for a freshly authenticated session.
+pub fn issue_token(config: &ResumeConfig, username: &str) -> rusqlite::Result<String> {
+    purge_expired_resume_tokens(config.window as i64)?;
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
+    add_resume_token(&hash_token(&token), username)?;
+    Ok(token)
+}
+
+/// Starts the resume window for a session that lost its connection.
+pub fn suspend(token: &str, rooms: &[String], last_message_id: i64) -> rusqlite::Result<()> {
+    set_resume_token_disconnected(&hash_token(token), Utc::now().timestamp(), rooms, last_message_id)
+}
+
+/// Invalidates the token of a session that ended on purpose.
+pub fn revoke(token: &str) -> rusqlite::Result<()> {
+    delete_resume_token(&hash_token(token))
+}
+
+/// Consumes the token, returning the previous session if it disconnected within the resume window.
+/// While the server has not noticed the old connection drop the token is kept, so the client can try again.
+pub fn redeem(config: &ResumeConfig, username: &str, token: &str) -> rusqlite::Result<Option<ResumedSession>> {
+    let Some(session) = take_resume_token(&hash_token(token), username)? else {
+        return Ok(None);
+    };
+    if Utc::now().timestamp() - session.disconnected_at > config.window as i64 {
+        return Ok(None);
+    }
+    Ok(Some(ResumedSession { last_message_id: session.last_message_id, rooms: session.rooms }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_message_to_db, add_or_update_user, get_messages_since, init_db};
+
+    #[test]
+    fn test_resume_flow() {
+        init_db().unwrap();
+        let config = ResumeConfig::default();
+
+        let token = issue_token(&config, "testuser").unwrap();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        // still connected, the token survives until the old connection is noticed gone
+        assert!(redeem(&config, "testuser", &token).unwrap().is_none());
+        suspend(&token, &["lobby".to_string()], 7).unwrap();
+        assert!(redeem(&config, "otheruser", &token).unwrap().is_none());
+        let session = redeem(&config, "testuser", &token).unwrap().unwrap();
+        assert_eq!(session.rooms, vec!["lobby".to_string()]);
+        assert_eq!(session.last_message_id, 7);
+        // single use
+        assert!(redeem(&config, "testuser", &token).unwrap().is_none());
+
+        let token = issue_token(&config, "testuser").unwrap();
+        suspend(&token, &[], 0).unwrap();
+        revoke(&token).unwrap();
+        assert!(redeem(&config, "testuser", &token).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_replay_starts_after_last_delivered_message() {
+        init_db().unwrap();
+        let config = ResumeConfig::default();
+        add_or_update_user("testuser");
+        let now = Utc::now().timestamp();
+        let delivered = add_message_to_db(now, "testuser", "global", "seen", None).unwrap();
+
+        let token = issue_token(&config, "testuser").unwrap();
+        suspend(&token, &[], delivered).unwrap();
+        // sent in the same second as the disconnect
+        add_message_to_db(now, "testuser", "global", "missed", None).unwrap();
+
+        let session = redeem(&config, "testuser", &token).unwrap().unwrap();
+        let missed = get_messages_since("testuser", &session.rooms, session.last_message_id, config.replay_limit).unwrap();
+        assert_eq!(missed.iter().map(|message| message.message.as_str()).collect::<Vec<_>>(), vec!["missed"]);
+    }
+}
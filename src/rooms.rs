@@ -0,0 +1,436 @@
+use std::fmt;
+use std::sync::Arc;
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use axum::extract::Json;
+use chrono::Utc;
+use serde::Serialize;
+use crate::admin::publish_moderation;
+use crate::conn_handler::send_to_clients;
+use crate::db::{
+    create_room, get_room, get_room_access, get_room_role, get_rooms, get_rooms_with_role, get_user, set_room_role,
+    update_room, Room,
+};
+use crate::state::{join_room, leave_room, members_of, sessions_of, Client};
+use crate::validators::validate_room_name;
+use crate::web_auth::hash_password;
+
+/// What a user is to a room, ordered by rank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoomRole {
+    Banned,
+    Invited,
+    Member,
+    Operator,
+    Owner,
+}
+
+impl RoomRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoomRole::Banned => "banned",
+            RoomRole::Invited => "invited",
+            RoomRole::Member => "member",
+            RoomRole::Operator => "operator",
+            RoomRole::Owner => "owner",
+        }
+    }
+
+    fn parse(role: &str) -> Option<Self> {
+        [RoomRole::Banned, RoomRole::Invited, RoomRole::Member, RoomRole::Operator, RoomRole::Owner]
+            .into_iter()
+            .find(|candidate| candidate.as_str() == role)
+    }
+
+    pub fn is_member(self) -> bool {
+        self >= RoomRole::Member
+    }
+}
+
+/// Why a room could not be joined or changed.
+#[derive(Debug, PartialEq)]
+pub enum RoomError {
+    InvalidName,
+    NoSuchRoom,
+    NoSuchUser,
+    Banned,
+    InviteOnly,
+    WrongPassword,
+    NotAMember,
+    NotAllowed,
+    Database,
+}
+
+impl RoomError {
+    /// The reason in `JOIN_FAILED:<room>:<reason>`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RoomError::InvalidName => "invalid_name",
+            RoomError::NoSuchRoom => "no_such_room",
+            RoomError::NoSuchUser => "no_such_user",
+            RoomError::Banned => "banned",
+            RoomError::InviteOnly => "invite_only",
+            RoomError::WrongPassword => "wrong_password",
+            RoomError::NotAMember => "not_a_member",
+            RoomError::NotAllowed => "not_allowed",
+            RoomError::Database => "error",
+        }
+    }
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RoomError::InvalidName => "That name can't be used for a room",
+            RoomError::NoSuchRoom => "No such room",
+            RoomError::NoSuchUser => "No such user",
+            RoomError::Banned => "Banned from this room",
+            RoomError::InviteOnly => "This room is invite only",
+            RoomError::WrongPassword => "Wrong room password",
+            RoomError::NotAMember => "Not a member of this room",
+            RoomError::NotAllowed => "You are not allowed to do that in this room",
+            RoomError::Database => "Something went wrong, try again later",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+fn database_error(e: rusqlite::Error) -> RoomError {
+    tracing::error!(target: "db", "Failed to access rooms: {}", e);
+    RoomError::Database
+}
+
+pub fn role_of(room: &str, username: &str) -> Result<Option<RoomRole>, RoomError> {
+    Ok(get_room_role(room, username).map_err(database_error)?.as_deref().and_then(RoomRole::parse))
+}
+
+/// Global and every room created by joining it.
+pub fn is_room(name: &str) -> bool {
+    name == "global" || (validate_room_name(name) && get_room(name).ok().flatten().is_some())
+}
+
+/// Whether `conversation` is a room `username` is not a member of. Global and direct conversations are open to everyone.
+pub fn is_locked_out(conversation: &str, username: &str) -> bool {
+    if conversation == "global" || !is_room(conversation) {
+        return false;
+    }
+    !role_of(conversation, username).ok().flatten().is_some_and(RoomRole::is_member)
+}
+
+/// Operators and the owner manage a room.
+pub fn can_manage(room: &str, username: &str) -> bool {
+    role_of(room, username).ok().flatten().is_some_and(|role| role >= RoomRole::Operator)
+}
+
+fn password_matches(room: &Room, password: Option<&str>) -> bool {
+    let Some(hash) = &room.password_hash else {
+        return true;
+    };
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    password.is_some_and(|password| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// What joining a room comes down to.
+#[derive(Debug, PartialEq)]
+pub enum JoinAccess {
+    // the room does not exist yet, joining creates it
+    Create,
+    // already a member, owner or operator
+    Rejoin,
+    Enter,
+}
+
+/// The room authorization check behind `JOIN`. Invited users skip the password.
+pub fn authorize_join(name: &str, username: &str, password: Option<&str>) -> Result<JoinAccess, RoomError> {
+    // room names start with #, a message to a name without one always is a direct message
+    if !validate_room_name(name) {
+        return Err(RoomError::InvalidName);
+    }
+    let Some(room) = get_room(name).map_err(database_error)? else {
+        return Ok(JoinAccess::Create);
+    };
+    match role_of(name, username)? {
+        Some(RoomRole::Banned) => Err(RoomError::Banned),
+        Some(role) if role.is_member() => Ok(JoinAccess::Rejoin),
+        Some(_) => Ok(JoinAccess::Enter),
+        None if room.invite_only => Err(RoomError::InviteOnly),
+        None if !password_matches(&room, password) => Err(RoomError::WrongPassword),
+        None => Ok(JoinAccess::Enter),
+    }
+}
+
+async fn sessions_on_protocol_2(username: &str) -> Vec<Arc<Client>> {
+    let mut sessions = sessions_of(username).await;
+    sessions.retain(|client| client.listener.sends_events());
+    sessions
+}
+
+/// Handles `JOIN:<room>[:<password>]`.
+pub async fn join(client: &Arc<Client>, username: &str, name: &str, password: Option<&str>) -> Result<(), RoomError> {
+    let now = Utc::now().timestamp();
+    match authorize_join(name, username, password)? {
+        JoinAccess::Create => {
+            let room = Room {
+                name: name.to_string(),
+                owner: username.to_string(),
+                invite_only: false,
+                hidden: false,
+                password_hash: None,
+                created_at: now,
+            };
+            create_room(&room).map_err(database_error)?;
+            tracing::info!(target: "server", "{} created room {}", username, name);
+        }
+        JoinAccess::Rejoin => {}
+        JoinAccess::Enter => set_room_role(name, username, Some(RoomRole::Member.as_str()), username, now).map_err(database_error)?,
+    }
+    // membership belongs to the user, so it applies to all of their connections
+    for session in sessions_of(username).await {
+        join_room(name, &session).await;
+    }
+    // the user's other connections are in the room now too
+    let mut others = sessions_on_protocol_2(username).await;
+    others.retain(|session| !Arc::ptr_eq(session, client));
+    send_to_clients(&others, &format!("JOINED:{}\n", name));
+    Ok(())
+}
+
+/// Handles `LEAVE:<room>`. Members have to join again, owners and operators keep their role.
+pub async fn leave(username: &str, name: &str) -> Result<(), RoomError> {
+    match role_of(name, username)? {
+        Some(RoomRole::Member) => set_room_role(name, username, None, username, Utc::now().timestamp()).map_err(database_error)?,
+        Some(role) if role.is_member() => {}
+        _ => return Err(RoomError::NotAMember),
+    }
+    for session in sessions_of(username).await {
+        leave_room(name, &session).await;
+    }
+    Ok(())
+}
+
+/// The rooms `username` is a member of, joined again when they log in.
+pub fn memberships(username: &str) -> Vec<String> {
+    get_rooms_with_role(username, &["owner", "operator", "member"]).unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to load the rooms of {}: {}", username, e);
+        Vec::new()
+    })
+}
+
+/// Puts `client` back into the `rooms` `username` still is a member of, returns those.
+pub async fn rejoin(client: &Arc<Client>, username: &str, rooms: &[String]) -> Vec<String> {
+    let mut joined = Vec::new();
+    for room in rooms {
+        if is_room(room) && !is_locked_out(room, username) {
+            join_room(room, client).await;
+            joined.push(room.clone());
+        }
+    }
+    joined
+}
+
+// the room and the caller's role in it, if that is at least `minimum`
+fn require_role(name: &str, username: &str, minimum: RoomRole) -> Result<(Room, RoomRole), RoomError> {
+    let room = get_room(name).map_err(database_error)?.ok_or(RoomError::NoSuchRoom)?;
+    match role_of(name, username)? {
+        Some(role) if role >= minimum => Ok((room, role)),
+        _ => Err(RoomError::NotAllowed),
+    }
+}
+
+fn registered(username: &str) -> Result<(), RoomError> {
+    match get_user(username).map_err(database_error)? {
+        Some(_) => Ok(()),
+        None => Err(RoomError::NoSuchUser),
+    }
+}
+
+/// Lets `target` into `room` without the password, also when it is invite only. Returns false if they already are in.
+pub async fn invite(room: &str, by: &str, target: &str) -> Result<bool, RoomError> {
+    require_role(room, by, RoomRole::Operator)?;
+    registered(target)?;
+    match role_of(room, target)? {
+        Some(RoomRole::Banned) => return Err(RoomError::Banned),
+        Some(role) if role >= RoomRole::Invited => return Ok(false),
+        _ => {}
+    }
+    set_room_role(room, target, Some(RoomRole::Invited.as_str()), by, Utc::now().timestamp()).map_err(database_error)?;
+    tracing::info!(target: "server", "{} invited {} to {}", by, target, room);
+    send_to_clients(&sessions_on_protocol_2(target).await, &format!("INVITED:{}:{}\n", room, by));
+    Ok(true)
+}
+
+/// Kicks `target` out of `room`, or bans them so they can't join again. Only users ranked below the caller can be removed.
+pub async fn remove(room: &str, by: &str, target: &str, ban: bool) -> Result<(), RoomError> {
+    let (_, role) = require_role(room, by, RoomRole::Operator)?;
+    let target_role = role_of(room, target)?;
+    if target_role.is_some_and(|target_role| target_role >= role) {
+        return Err(RoomError::NotAllowed);
+    }
+    let now = Utc::now().timestamp();
+    if ban {
+        registered(target)?;
+        set_room_role(room, target, Some(RoomRole::Banned.as_str()), by, now).map_err(database_error)?;
+    } else if target_role.is_some_and(|target_role| target_role >= RoomRole::Invited) {
+        set_room_role(room, target, None, by, now).map_err(database_error)?;
+    } else {
+        return Err(RoomError::NotAMember);
+    }
+
+    let action = if ban { "room_ban" } else { "room_kick" };
+    tracing::info!(target: "server", "{} removed {} from {} ({})", by, target, room, action);
+    publish_moderation(action, format!("user {}", target), room);
+    for session in sessions_of(target).await {
+        leave_room(room, &session).await;
+    }
+    send_to_clients(&sessions_on_protocol_2(target).await, &format!("REMOVED_FROM_ROOM:{}\n", room));
+    Ok(())
+}
+
+/// Lifts a ban, returns false if `target` was not banned.
+pub fn unban(room: &str, by: &str, target: &str) -> Result<bool, RoomError> {
+    require_role(room, by, RoomRole::Operator)?;
+    if role_of(room, target)? != Some(RoomRole::Banned) {
+        return Ok(false);
+    }
+    set_room_role(room, target, None, by, Utc::now().timestamp()).map_err(database_error)?;
+    publish_moderation("room_unban", format!("user {}", target), room);
+    Ok(true)
+}
+
+/// Makes a member an operator or back, only the owner can.
+pub fn set_operator(room: &str, by: &str, target: &str, operator: bool) -> Result<(), RoomError> {
+    require_role(room, by, RoomRole::Owner)?;
+    match role_of(room, target)? {
+        Some(RoomRole::Member | RoomRole::Operator) => {}
+        Some(RoomRole::Owner) => return Err(RoomError::NotAllowed),
+        _ => return Err(RoomError::NotAMember),
+    }
+    let role = if operator { RoomRole::Operator } else { RoomRole::Member };
+    set_room_role(room, target, Some(role.as_str()), by, Utc::now().timestamp()).map_err(database_error)
+}
+
+/// A room setting only the owner can change.
+pub enum RoomSetting {
+    InviteOnly(bool),
+    Hidden(bool),
+    // None removes the password
+    Password(Option<String>),
+}
+
+pub fn configure(name: &str, by: &str, setting: RoomSetting) -> Result<(), RoomError> {
+    let (mut room, _) = require_role(name, by, RoomRole::Owner)?;
+    match setting {
+        RoomSetting::InviteOnly(invite_only) => room.invite_only = invite_only,
+        RoomSetting::Hidden(hidden) => room.hidden = hidden,
+        RoomSetting::Password(password) => {
+            room.password_hash = match password {
+                Some(password) => Some(hash_password(&password).map_err(|e| {
+                    tracing::error!(target: "server", "Failed to hash the password of room {}: {}", name, e);
+                    RoomError::Database
+                })?),
+                None => None,
+            };
+        }
+    }
+    update_room(&room).map_err(database_error)?;
+    tracing::info!(target: "server", "{} changed the settings of room {}", by, name);
+    Ok(())
+}
+
+/// The rooms listed to `username`: every room that is not hidden, and the hidden ones they are a member of.
+pub fn visible_rooms(username: &str) -> Vec<Room> {
+    let rooms = get_rooms().unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to list rooms: {}", e);
+        Vec::new()
+    });
+    rooms.into_iter().filter(|room| !room.hidden || !is_locked_out(&room.name, username)).collect()
+}
+
+/// Everyone with a role in `room`, grouped by role from the owner down.
+pub fn access_list(room: &str) -> Vec<(RoomRole, Vec<String>)> {
+    let access = get_room_access(room).unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to load the access list of {}: {}", room, e);
+        Vec::new()
+    });
+    let mut grouped: Vec<(RoomRole, Vec<String>)> = Vec::new();
+    for (username, role) in access {
+        let Some(role) = RoomRole::parse(&role) else {
+            continue;
+        };
+        match grouped.iter_mut().find(|(group, _)| *group == role) {
+            Some((_, usernames)) => usernames.push(username),
+            None => grouped.push((role, vec![username])),
+        }
+    }
+    grouped.sort_by_key(|(role, _)| std::cmp::Reverse(*role));
+    grouped
+}
+
+#[derive(Serialize)]
+pub struct RoomSummary {
+    #[serde(flatten)]
+    room: Room,
+    password: bool,
+    online: usize,
+}
+
+/// `GET /api/rooms`, every room including the hidden ones.
+pub async fn rooms_handler() -> Json<Vec<RoomSummary>> {
+    let rooms = get_rooms().unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to list rooms: {}", e);
+        Vec::new()
+    });
+    let mut summaries = Vec::new();
+    for room in rooms {
+        let online = members_of(&room.name).await.len();
+        summaries.push(RoomSummary { password: room.password_hash.is_some(), room, online });
+    }
+    Json(summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{seed_test_room, seed_test_users};
+
+    #[tokio::test]
+    async fn test_room_authorization() {
+        seed_test_users(&["owner", "op", "alice", "bob"]);
+        assert_eq!(authorize_join("global", "alice", None), Err(RoomError::InvalidName));
+        assert_eq!(authorize_join("secret", "alice", None), Err(RoomError::InvalidName));
+        assert_eq!(authorize_join("#secret", "owner", None), Ok(JoinAccess::Create));
+        // users and rooms don't share names, a room named after a user leaves their direct messages alone
+        assert_eq!(authorize_join("#bob", "alice", None), Ok(JoinAccess::Create));
+        assert!(!is_room("bob"));
+        seed_test_room("#secret", "owner");
+        assert_eq!(authorize_join("#secret", "owner", None), Ok(JoinAccess::Rejoin));
+        assert!(is_locked_out("#secret", "alice"));
+
+        configure("#secret", "owner", RoomSetting::Password(Some("hunter2".to_string()))).unwrap();
+        assert_eq!(authorize_join("#secret", "alice", Some("wrong")), Err(RoomError::WrongPassword));
+        assert_eq!(authorize_join("#secret", "alice", Some("hunter2")), Ok(JoinAccess::Enter));
+        configure("#secret", "owner", RoomSetting::InviteOnly(true)).unwrap();
+        assert_eq!(authorize_join("#secret", "alice", Some("hunter2")), Err(RoomError::InviteOnly));
+        assert_eq!(configure("#secret", "alice", RoomSetting::Hidden(true)), Err(RoomError::NotAllowed));
+
+        assert_eq!(invite("#secret", "owner", "alice").await, Ok(true));
+        assert_eq!(authorize_join("#secret", "alice", None), Ok(JoinAccess::Enter));
+        set_room_role("#secret", "op", Some("member"), "op", 100).unwrap();
+        set_operator("#secret", "owner", "op", true).unwrap();
+        assert!(can_manage("#secret", "op"));
+
+        // operators can't remove each other or the owner, but anyone below them
+        assert_eq!(remove("#secret", "op", "owner", true).await, Err(RoomError::NotAllowed));
+        remove("#secret", "op", "bob", true).await.unwrap();
+        assert_eq!(authorize_join("#secret", "bob", None), Err(RoomError::Banned));
+        assert_eq!(invite("#secret", "op", "bob").await, Err(RoomError::Banned));
+        assert_eq!(unban("#secret", "op", "bob"), Ok(true));
+        assert_eq!(unban("#secret", "op", "bob"), Ok(false));
+
+        let access = access_list("#secret");
+        assert_eq!(access[0], (RoomRole::Owner, vec!["owner".to_string()]));
+        assert_eq!(access[1], (RoomRole::Operator, vec!["op".to_string()]));
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::signal;
+use tokio::time::{sleep, Duration, Instant};
+use crate::config::ShutdownConfig;
+use crate::conn_handler::send_to_all_connections;
+use crate::db::mark_all_users_offline;
+use crate::state::get_active_connections;
+
+static CLOSING: AtomicBool = AtomicBool::new(false);
+static RESTARTING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrainReason {
+    Shutdown,
+    // a new process took over the listeners, clients should reconnect right away
+    Restart,
+}
+
+// remaining seconds at which the shutdown notice is repeated
+const COUNTDOWN_STEPS: [u64; 9] = [300, 60, 30, 10, 5, 4, 3, 2, 1];
+
+/// Whether connection handlers should say goodbye and close their socket.
+pub fn is_closing() -> bool {
+    CLOSING.load(Ordering::Relaxed)
+}
+
+/// The last line sent to clients before their connection is closed.
+pub fn closing_frame() -> &'static [u8] {
+    if RESTARTING.load(Ordering::Relaxed) {
+        b"SERVER_RESTART\n"
+    } else {
+        b"SERVER_SHUTDOWN\n"
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix (which is what Docker sends).
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+            }
+            Err(e) => {
+                tracing::error!(target: "shutdown", "Failed to listen for SIGTERM: {}", e);
+                let _ = signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
+
+/// Counts down to the shutdown or restart while notifying clients, then closes every connection
+/// and waits up to `drain_timeout` for the handlers to record their sessions.
+/// The listener must already be closed when this is called.
+pub async fn drain_connections(config: &ShutdownConfig, reason: DrainReason) {
+    let (countdown, notice, frame) = match reason {
+        DrainReason::Shutdown => (config.countdown, &config.notice, "SERVER_SHUTDOWN_IN"),
+        DrainReason::Restart => (config.restart_countdown, &config.restart_notice, "SERVER_RESTART_IN"),
+    };
+    RESTARTING.store(reason == DrainReason::Restart, Ordering::Relaxed);
+
+    let deadline = Instant::now() + Duration::from_secs(countdown);
+    let mut remaining = countdown;
+    while remaining > 0 {
+        let notice = format!("{}:{}:{}\n", frame, remaining, notice);
+        send_to_all_connections(&notice).await;
+
+        let next_step = COUNTDOWN_STEPS.iter().copied().find(|step| *step < remaining).unwrap_or(0);
+        sleep(deadline.saturating_duration_since(Instant::now()).saturating_sub(Duration::from_secs(next_step))).await;
+        remaining = next_step;
+    }
+
+    tracing::info!(target: "shutdown", "Closing all connections");
+    CLOSING.store(true, Ordering::Relaxed);
+
+    let drain_deadline = Instant::now() + Duration::from_secs(config.drain_timeout);
+    loop {
+        let open_connections = get_active_connections().read().await.len();
+        if open_connections == 0 {
+            tracing::info!(target: "shutdown", "All connections closed");
+            break;
+        }
+        if Instant::now() >= drain_deadline {
+            tracing::warn!(target: "shutdown", "{} connections did not close within the drain timeout", open_connections);
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // handlers that are still stuck record their session time when the runtime drops them.
+    // on restart the users may already be back online on the new process
+    if reason == DrainReason::Restart {
+        return;
+    }
+    if let Err(e) = mark_all_users_offline() {
+        tracing::error!(target: "shutdown", "Failed to mark users offline: {}", e);
+    }
+}
@@ -1,19 +1,200 @@
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
 use std::collections::HashMap;
-use tokio::net::TcpStream;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncWrite};
 use lazy_static::lazy_static;
+use chrono::Utc;
+use serde::Serialize;
 
-type ActiveConnections = Arc<RwLock<Vec<Arc<tokio::sync::Mutex<TcpStream>>>>>;
-pub(crate) type ActiveUsers = Arc<RwLock<HashMap<String, Arc<tokio::sync::Mutex<TcpStream>>>>>;
-type ChatRooms = Arc<RwLock<HashMap<String, Vec<Arc<tokio::sync::Mutex<TcpStream>>>>>>;
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+type ActiveConnections = Arc<RwLock<Vec<Arc<Client>>>>;
+// every session of a user, more than one only with the allow_multiple session policy
+pub(crate) type ActiveUsers = Arc<RwLock<HashMap<String, Vec<Arc<Client>>>>>;
+type ChatRooms = Arc<RwLock<HashMap<String, Vec<Arc<Client>>>>>;
+
+/// Anything a client can be connected over: plain TCP, TLS or a Unix socket.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
+
+/// Where a client connects from, Unix socket peers have no address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Ip(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+/// Settings of the listener a client came in through.
+#[derive(Debug)]
+pub struct ListenerOptions {
+    pub name: String,
+    pub tls: bool,
+    pub require_password: bool,
+    pub protocol_version: u8,
+}
+
+impl ListenerOptions {
+    /// Whether clients on this listener are sent more than chat lines.
+    pub fn sends_events(&self) -> bool {
+        sends_events(self.protocol_version)
+    }
+}
+
+/// Protocol 1 clients only understand chat lines, typing, reactions, topics and the like are protocol 2 frames.
+pub fn sends_events(protocol_version: u8) -> bool {
+    protocol_version >= 2
+}
+
+/// A connected socket and what the server knows about it.
+pub struct Client {
+    pub id: u64,
+    pub peer_addr: PeerAddr,
+    pub listener: Arc<ListenerOptions>,
+    pub connected_at: i64,
+    socket: Mutex<Box<dyn ClientStream>>,
+    username: std::sync::Mutex<Option<String>>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    // id of the newest chat message written to the socket, a resumed session replays what came after it
+    last_message_id: AtomicI64,
+    // set when an admin wants the connection gone, the handler closes it on its next turn
+    close_reason: std::sync::Mutex<Option<String>>,
+}
+
+/// Snapshot of a connection for the admin API.
+#[derive(Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer_addr: String,
+    pub listener: String,
+    pub tls: bool,
+    pub protocol_version: u8,
+    pub connected_at: i64,
+    pub authenticated: bool,
+    pub username: Option<String>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Exclusive access to the socket of a client, derefs to its stream.
+pub struct ClientSocket<'a> {
+    client: &'a Client,
+    stream: MutexGuard<'a, Box<dyn ClientStream>>,
+}
+
+impl Client {
+    pub fn new(id: u64, peer_addr: PeerAddr, listener: Arc<ListenerOptions>, socket: Box<dyn ClientStream>) -> Self {
+        Client {
+            id,
+            peer_addr,
+            listener,
+            connected_at: Utc::now().timestamp(),
+            socket: Mutex::new(socket),
+            username: std::sync::Mutex::new(None),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            last_message_id: AtomicI64::new(0),
+            close_reason: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub async fn lock(&self) -> ClientSocket<'_> {
+        ClientSocket { client: self, stream: self.socket.lock().await }
+    }
+
+    /// The user this connection authenticated as, if it did.
+    pub fn username(&self) -> Option<String> {
+        self.username.lock().unwrap().clone()
+    }
+
+    pub fn set_username(&self, username: &str) {
+        *self.username.lock().unwrap() = Some(username.to_string());
+    }
+
+    pub fn add_bytes_received(&self, count: usize) {
+        self.bytes_received.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Records that the chat message `id` went out to this connection.
+    pub fn delivered(&self, id: i64) {
+        self.last_message_id.fetch_max(id, Ordering::Relaxed);
+    }
+
+    pub fn last_message_id(&self) -> i64 {
+        self.last_message_id.load(Ordering::Relaxed)
+    }
+
+    /// Asks the connection handler to send `KICKED:<reason>` and close the connection.
+    pub fn request_close(&self, reason: &str) {
+        self.close_reason.lock().unwrap().get_or_insert_with(|| reason.to_string());
+    }
+
+    pub fn close_reason(&self) -> Option<String> {
+        self.close_reason.lock().unwrap().clone()
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        let username = self.username();
+        ConnectionInfo {
+            id: self.id,
+            peer_addr: self.peer_addr.to_string(),
+            listener: self.listener.name.clone(),
+            tls: self.listener.tls,
+            protocol_version: self.listener.protocol_version,
+            connected_at: self.connected_at,
+            authenticated: username.is_some(),
+            username,
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ClientSocket<'_> {
+    pub fn add_bytes_sent(&self, count: usize) {
+        self.client.bytes_sent.fetch_add(count as u64, Ordering::Relaxed);
+    }
+}
+
+impl Deref for ClientSocket<'_> {
+    type Target = Box<dyn ClientStream>;
+
+    fn deref(&self) -> &Box<dyn ClientStream> {
+        &self.stream
+    }
+}
+
+impl DerefMut for ClientSocket<'_> {
+    fn deref_mut(&mut self) -> &mut Box<dyn ClientStream> {
+        &mut self.stream
+    }
+}
 
 lazy_static! {
     pub static ref ACTIVE_CONNECTIONS: ActiveConnections = Arc::new(RwLock::new(Vec::new()));
     pub static ref CHAT_ROOMS: ChatRooms = Arc::new(RwLock::new(HashMap::new()));
     pub static ref ACTIVE_USERS: ActiveUsers = Arc::new(RwLock::new(HashMap::new()));
+    pub static ref SERVER_START_TIME: i64 = Utc::now().timestamp();
 }
 
+// tests that put sessions into the shared state hold this, so they don't see each other's
+#[cfg(test)]
+pub(crate) static TEST_STATE_LOCK: Mutex<()> = Mutex::const_new(());
+
 pub fn get_active_connections() -> ActiveConnections {
     Arc::clone(&ACTIVE_CONNECTIONS)
 }
@@ -26,6 +207,81 @@ pub fn get_active_users() -> ActiveUsers {
     Arc::clone(&ACTIVE_USERS)
 }
 
+/// The connections `username` is logged in with.
+pub async fn sessions_of(username: &str) -> Vec<Arc<Client>> {
+    get_active_users().read().await.get(username).cloned().unwrap_or_default()
+}
+
+/// Forgets one session of `username`, the user stays online as long as they have another.
+pub async fn remove_session(username: &str, client: &Arc<Client>) {
+    let active_users = get_active_users();
+    let mut users = active_users.write().await;
+    if let Some(sessions) = users.get_mut(username) {
+        sessions.retain(|session| !Arc::ptr_eq(session, client));
+        if sessions.is_empty() {
+            users.remove(username);
+        }
+    }
+}
+
+/// Everyone in a room, every authenticated connection for global.
+pub async fn members_of(room: &str) -> Vec<Arc<Client>> {
+    if room == "global" {
+        return get_active_users().read().await.values().flatten().cloned().collect();
+    }
+    get_chat_rooms().read().await.get(room).cloned().unwrap_or_default()
+}
+
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub async fn find_connection(id: u64) -> Option<Arc<Client>> {
+    get_active_connections().read().await
+        .iter()
+        .find(|client| client.id == id)
+        .cloned()
+}
+
+/// Names of the chat rooms the client is a member of.
+pub async fn get_rooms_of(client: &Arc<Client>) -> Vec<String> {
+    get_chat_rooms().read().await
+        .iter()
+        .filter(|(_, members)| members.iter().any(|member| Arc::ptr_eq(member, client)))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+pub async fn join_room(name: &str, client: &Arc<Client>) {
+    let chat_rooms = get_chat_rooms();
+    let mut chat_rooms = chat_rooms.write().await;
+    let members = chat_rooms.entry(name.to_string()).or_default();
+    if !members.iter().any(|member| Arc::ptr_eq(member, client)) {
+        members.push(Arc::clone(client));
+    }
+}
+
+pub async fn leave_room(name: &str, client: &Arc<Client>) {
+    let chat_rooms = get_chat_rooms();
+    let mut chat_rooms = chat_rooms.write().await;
+    if let Some(members) = chat_rooms.get_mut(name) {
+        members.retain(|member| !Arc::ptr_eq(member, client));
+    }
+}
+
+pub async fn leave_all_rooms(client: &Arc<Client>) {
+    let chat_rooms = get_chat_rooms();
+    let mut chat_rooms = chat_rooms.write().await;
+    for members in chat_rooms.values_mut() {
+        members.retain(|member| !Arc::ptr_eq(member, client));
+    }
+}
+
+/// Seconds elapsed since the server started.
+pub fn get_uptime() -> i64 {
+    Utc::now().timestamp() - *SERVER_START_TIME
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -36,6 +292,7 @@ mod tests {
         let rt = Runtime::new().unwrap();
 
         rt.block_on(async {
+            let _state = TEST_STATE_LOCK.lock().await;
             let active_connections = get_active_connections();
             let active_users = get_active_users();
             let chat_rooms = get_chat_rooms();
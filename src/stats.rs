@@ -0,0 +1,151 @@
+use axum::{
+    extract::{Json, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::db::{get_message_counts, get_top_posters, record_online_users};
+use crate::web_auth::error_response;
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+const MAX_BUCKETS: i64 = 24 * 90;
+const DEFAULT_TOP_POSTERS: i64 = 10;
+const MAX_TOP_POSTERS: i64 = 100;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Interval {
+    fn seconds(self) -> i64 {
+        match self {
+            Interval::Hour => HOUR,
+            Interval::Day => DAY,
+        }
+    }
+
+    // the last day of hours or the last month of days
+    fn default_buckets(self) -> i64 {
+        match self {
+            Interval::Hour => 24,
+            Interval::Day => 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct SeriesQuery {
+    #[serde(default)]
+    interval: Interval,
+    buckets: Option<i64>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct TopPostersQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SeriesPoint {
+    // unix timestamp of the start of the bucket
+    start: i64,
+    messages: i64,
+}
+
+#[derive(Serialize)]
+pub struct TopPoster {
+    username: String,
+    messages_sent: i64,
+}
+
+/// Records the number of users online after one logged in, to keep track of the peak.
+pub fn user_came_online(online_users: usize) {
+    if let Err(e) = record_online_users(online_users, Utc::now().timestamp()) {
+        tracing::error!(target: "db", "Failed to record online users: {}", e);
+    }
+}
+
+/// Message counts for the `buckets` most recent intervals up to `now`, oldest first and including empty ones.
+pub fn message_series(interval: Interval, buckets: i64, now: i64) -> rusqlite::Result<Vec<SeriesPoint>> {
+    let size = interval.seconds();
+    let first = (now / size - (buckets - 1)) * size;
+    let counts = get_message_counts(size, first)?;
+
+    Ok((0..buckets)
+        .map(|index| {
+            let start = first + index * size;
+            let messages = counts.iter()
+                .find(|(bucket, _)| *bucket == start)
+                .map_or(0, |(_, count)| *count);
+            SeriesPoint { start, messages }
+        })
+        .collect())
+}
+
+/// `/api/stats/messages`, messages per hour or per day.
+pub async fn message_series_handler(query: Option<Query<SeriesQuery>>) -> Response {
+    let Some(Query(query)) = query else {
+        return error_response(StatusCode::BAD_REQUEST, "interval must be hour or day");
+    };
+    let buckets = query.buckets.unwrap_or(query.interval.default_buckets()).clamp(1, MAX_BUCKETS);
+    match message_series(query.interval, buckets, Utc::now().timestamp()) {
+        Ok(series) => Json(series).into_response(),
+        Err(e) => {
+            tracing::error!(target: "webserver", "Failed to load message statistics: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+/// `/api/stats/top-posters`, the users with the most messages sent.
+pub async fn top_posters_handler(query: Option<Query<TopPostersQuery>>) -> Response {
+    let Some(Query(query)) = query else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid query");
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_TOP_POSTERS).clamp(1, MAX_TOP_POSTERS);
+    match get_top_posters(limit) {
+        Ok(posters) => {
+            let posters: Vec<TopPoster> = posters.into_iter()
+                .map(|(username, messages_sent)| TopPoster { username, messages_sent })
+                .collect();
+            Json(posters).into_response()
+        }
+        Err(e) => {
+            tracing::error!(target: "webserver", "Failed to load top posters: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_message_to_db, seed_test_users};
+
+    #[test]
+    fn test_message_series() {
+        seed_test_users(&["alice"]);
+        let now = 10 * DAY + 5 * HOUR + 30;
+        add_message_to_db(now - 10, "alice", "global", "one", None).unwrap();
+        add_message_to_db(now - 20, "alice", "global", "two", None).unwrap();
+        add_message_to_db(now - 2 * HOUR, "alice", "global", "three", None).unwrap();
+        add_message_to_db(now - 3 * DAY, "alice", "global", "old", None).unwrap();
+
+        let hourly = message_series(Interval::Hour, 3, now).unwrap();
+        assert_eq!(hourly, vec![
+            SeriesPoint { start: 10 * DAY + 3 * HOUR, messages: 1 },
+            SeriesPoint { start: 10 * DAY + 4 * HOUR, messages: 0 },
+            SeriesPoint { start: 10 * DAY + 5 * HOUR, messages: 2 },
+        ]);
+
+        let daily = message_series(Interval::Day, 4, now).unwrap();
+        assert_eq!(daily.iter().map(|point| point.messages).collect::<Vec<_>>(), vec![1, 0, 0, 3]);
+        assert_eq!(daily[3].start, 10 * DAY);
+    }
+}
@@ -1,7 +1,22 @@
+use crate::db::StoredMessage;
+use crate::state::sends_events;
+
+#[allow(clippy::needless_return)]
 pub fn format_outgoing_message(username: &str, recipient: &str, command_message: &str, timestamp: i64) -> String {
     return format!("{}:{}:{}:{}", timestamp, username, recipient, command_message)
 }
 
+/// The line a chat message is delivered as. Protocol 2 clients also get the id to refer back to it,
+/// and replies come as `REPLY` frames with the id of their thread.
+pub fn format_message_frame(message: &StoredMessage, protocol_version: u8) -> String {
+    let line = format_outgoing_message(&message.sender, &message.recipient, &message.message, message.timestamp);
+    match message.parent_id {
+        _ if !sends_events(protocol_version) => format!("{}\n", line),
+        Some(parent_id) => format!("REPLY:{}:{}:{}\n", message.id, parent_id, line),
+        None => format!("MESSAGE:{}:{}\n", message.id, line),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -16,4 +31,22 @@ mod tests {
         let formatted_message = format_outgoing_message(username, recipient, command_message, timestamp);
         assert_eq!(formatted_message, format!("{}:{}:{}:{}", timestamp, username, recipient, command_message));
     }
+
+    #[test]
+    fn test_format_message_frame() {
+        let message = StoredMessage {
+            id: 42,
+            timestamp: 1000,
+            sender: "alice".to_string(),
+            recipient: "bob".to_string(),
+            message: "hi: there".to_string(),
+            parent_id: None,
+        };
+        assert_eq!(format_message_frame(&message, 1), "1000:alice:bob:hi: there\n");
+        assert_eq!(format_message_frame(&message, 2), "MESSAGE:42:1000:alice:bob:hi: there\n");
+
+        let reply = StoredMessage { id: 43, parent_id: Some(42), ..message };
+        assert_eq!(format_message_frame(&reply, 1), "1000:alice:bob:hi: there\n");
+        assert_eq!(format_message_frame(&reply, 2), "REPLY:43:42:1000:alice:bob:hi: there\n");
+    }
 }
@@ -0,0 +1,65 @@
+use std::sync::Arc;
+use crate::activity::conversation_of;
+use crate::blocks;
+use crate::db::{get_message, get_thread, StoredMessage};
+use crate::state::{get_rooms_of, Client};
+
+// GET_THREAD sends the first message and at most this many replies
+pub const THREAD_LIMIT: i64 = 100;
+
+/// Where a reply goes.
+pub struct ReplyTarget {
+    // the first message of the thread, replies to replies end up in the same thread
+    pub thread_id: i64,
+    pub recipient: String,
+}
+
+// `message_id` if `username` may see it, loaded from the database
+async fn visible_message(client: &Arc<Client>, username: &str, message_id: &str) -> Option<StoredMessage> {
+    let id = message_id.parse::<i64>().ok()?;
+    let message = get_message(id).unwrap_or_else(|e| {
+        tracing::error!(target: "db", "Failed to load message {}: {}", id, e);
+        None
+    })?;
+    conversation_of(&message, username, &get_rooms_of(client).await)?;
+    Some(message)
+}
+
+/// Resolves the parent of `REPLY:<message-id>:<text>`. A reply goes to the conversation of its parent:
+/// global, the room, or the other side of a direct message.
+pub async fn reply_target(client: &Arc<Client>, username: &str, parent_id: &str) -> Option<ReplyTarget> {
+    let parent = visible_message(client, username, parent_id).await?;
+    let recipient = conversation_of(&parent, username, &get_rooms_of(client).await)?;
+    Some(ReplyTarget { thread_id: parent.parent_id.unwrap_or(parent.id), recipient })
+}
+
+/// The thread `message_id` belongs to for `GET_THREAD`, None if it does not exist or is not for `username`'s eyes.
+pub async fn thread(client: &Arc<Client>, username: &str, message_id: &str) -> Option<Vec<StoredMessage>> {
+    let message = visible_message(client, username, message_id).await?;
+    get_thread(message.parent_id.unwrap_or(message.id), THREAD_LIMIT)
+        .map(|thread| blocks::visible_to(username, thread))
+        .map_err(|e| tracing::error!(target: "db", "Failed to load thread of {}: {}", message.id, e))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{add_message_to_db, get_thread, seed_test_users};
+
+    #[test]
+    fn test_get_thread() {
+        seed_test_users(&["alice", "bob"]);
+        let root = add_message_to_db(100, "alice", "global", "anyone up for lunch?", None).unwrap();
+        add_message_to_db(110, "bob", "global", "unrelated", None).unwrap();
+        let first = add_message_to_db(120, "bob", "global", "me", Some(root)).unwrap();
+        let second = add_message_to_db(130, "alice", "global", "great", Some(root)).unwrap();
+
+        let thread = get_thread(root, 10).unwrap();
+        assert_eq!(thread.iter().map(|message| message.id).collect::<Vec<_>>(), vec![root, first, second]);
+        assert_eq!(thread[0].parent_id, None);
+        assert_eq!(thread[2].parent_id, Some(root));
+        // the first message is always there, replies are cut off from the oldest
+        let thread = get_thread(root, 1).unwrap();
+        assert_eq!(thread.iter().map(|message| message.id).collect::<Vec<_>>(), vec![root, second]);
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+use axum::{
+    extract::{Json, Path},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::admin::{publish_moderation, single_line};
+use crate::conn_handler::{send_to_clients, write_to_socket};
+use crate::db::{add_pin, get_message, get_pins, get_topic, get_user, remove_pin, set_topic, StoredMessage, Topic};
+use crate::state::{members_of, sends_events, ClientSocket};
+use crate::textutils::format_outgoing_message;
+use crate::rooms::{can_manage, is_room};
+use crate::web_auth::error_response;
+
+// pins are sent on every login, keep the list short
+pub const MAX_PINS: usize = 10;
+const MAX_TOPIC_LENGTH: usize = 200;
+// recorded as the author of changes made through the web UI API
+const WEB_UI: &str = "web UI";
+
+/// Why a topic or pin could not be changed.
+#[derive(Debug, PartialEq)]
+pub enum RoomInfoError {
+    NotARoom,
+    TopicTooLong,
+    NotInRoom,
+    TooManyPins,
+    Database,
+}
+
+impl fmt::Display for RoomInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RoomInfoError::NotARoom => "Topics and pins are only for global and rooms",
+            RoomInfoError::TopicTooLong => "The topic is too long",
+            RoomInfoError::NotInRoom => "No such message in this room",
+            RoomInfoError::TooManyPins => "Too many pinned messages, unpin one first",
+            RoomInfoError::Database => "Something went wrong, try again later",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+fn database_error(e: rusqlite::Error) -> RoomInfoError {
+    tracing::error!(target: "db", "Failed to update room info: {}", e);
+    RoomInfoError::Database
+}
+
+pub fn is_moderator(username: &str) -> bool {
+    get_user(username).ok().flatten().is_some_and(|user| matches!(user.permission.as_str(), "moderator" | "admin"))
+}
+
+/// Server moderators change topics and pins everywhere, room operators in their room.
+pub fn can_moderate(room: &str, username: &str) -> bool {
+    is_moderator(username) || can_manage(room, username)
+}
+
+fn pinned_frame(room: &str, message: &StoredMessage) -> String {
+    let line = format_outgoing_message(&message.sender, &message.recipient, &message.message, message.timestamp);
+    format!("PINNED:{}:{}:{}\n", room, message.id, line)
+}
+
+// topic and pin changes are announced to the protocol 2 clients in the room
+async fn announce(room: &str, frame: &str) {
+    let mut members = members_of(room).await;
+    members.retain(|client| client.listener.sends_events());
+    send_to_clients(&members, frame);
+}
+
+/// Sets the topic of `room`, None or an empty topic removes it.
+pub async fn change_topic(room: &str, topic: Option<&str>, set_by: &str) -> Result<(), RoomInfoError> {
+    if !is_room(room) {
+        return Err(RoomInfoError::NotARoom);
+    }
+    let topic = topic.map(single_line).filter(|topic| !topic.is_empty());
+    if topic.as_ref().is_some_and(|topic| topic.chars().count() > MAX_TOPIC_LENGTH) {
+        return Err(RoomInfoError::TopicTooLong);
+    }
+    set_topic(room, topic.as_deref(), set_by, Utc::now().timestamp()).map_err(database_error)?;
+
+    let topic = topic.unwrap_or_default();
+    tracing::info!(target: "server", "{} set the topic of {} to \"{}\"", set_by, room, topic);
+    publish_moderation("topic", room.to_string(), &topic);
+    announce(room, &format!("TOPIC:{}:{}\n", room, topic)).await;
+    Ok(())
+}
+
+/// Pins a message sent to `room`, returns false if it already was pinned.
+pub async fn pin(room: &str, message_id: i64, pinned_by: &str) -> Result<bool, RoomInfoError> {
+    if !is_room(room) {
+        return Err(RoomInfoError::NotARoom);
+    }
+    let message = get_message(message_id).map_err(database_error)?
+        .filter(|message| message.recipient == room)
+        .ok_or(RoomInfoError::NotInRoom)?;
+    if get_pins(room).map_err(database_error)?.len() >= MAX_PINS {
+        return Err(RoomInfoError::TooManyPins);
+    }
+    if !add_pin(room, message_id, pinned_by, Utc::now().timestamp()).map_err(database_error)? {
+        return Ok(false);
+    }
+
+    tracing::info!(target: "server", "{} pinned message {} in {}", pinned_by, message_id, room);
+    publish_moderation("pin", room.to_string(), &message_id.to_string());
+    announce(room, &pinned_frame(room, &message)).await;
+    Ok(true)
+}
+
+/// Unpins a message, returns false if it was not pinned.
+pub async fn unpin(room: &str, message_id: i64, unpinned_by: &str) -> Result<bool, RoomInfoError> {
+    if !is_room(room) {
+        return Err(RoomInfoError::NotARoom);
+    }
+    if !remove_pin(room, message_id).map_err(database_error)? {
+        return Ok(false);
+    }
+
+    tracing::info!(target: "server", "{} unpinned message {} in {}", unpinned_by, message_id, room);
+    publish_moderation("unpin", room.to_string(), &message_id.to_string());
+    announce(room, &format!("UNPINNED:{}:{}\n", room, message_id)).await;
+    Ok(true)
+}
+
+/// Sends the topic and pins of `room` to a protocol 2 client that just logged in or joined it.
+pub async fn send_room_info(room: &str, protocol_version: u8, socket: &mut ClientSocket<'_>) {
+    if !sends_events(protocol_version) {
+        return;
+    }
+    let (topic, pins) = match (get_topic(room), get_pins(room)) {
+        (Ok(topic), Ok(pins)) => (topic, pins),
+        (Err(e), _) | (_, Err(e)) => {
+            tracing::error!(target: "db", "Failed to load the topic and pins of {}: {}", room, e);
+            return;
+        }
+    };
+    if let Some(topic) = topic {
+        let _ = write_to_socket(socket, format!("TOPIC:{}:{}\n", room, topic.topic).as_bytes()).await;
+    }
+    for message in &pins {
+        let _ = write_to_socket(socket, pinned_frame(room, message).as_bytes()).await;
+    }
+}
+
+#[derive(Serialize)]
+pub struct RoomInfo {
+    name: String,
+    topic: Option<Topic>,
+    pins: Vec<StoredMessage>,
+}
+
+#[derive(Deserialize)]
+pub struct TopicRequest {
+    #[serde(default)]
+    topic: String,
+}
+
+#[derive(Deserialize)]
+pub struct PinRequest {
+    message_id: i64,
+}
+
+fn room_info_error_response(error: RoomInfoError) -> Response {
+    let status = match error {
+        RoomInfoError::NotARoom | RoomInfoError::NotInRoom => StatusCode::NOT_FOUND,
+        RoomInfoError::TopicTooLong => StatusCode::BAD_REQUEST,
+        RoomInfoError::TooManyPins => StatusCode::CONFLICT,
+        RoomInfoError::Database => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_response(status, &error.to_string())
+}
+
+/// `GET /api/rooms/:name`, the topic and pinned messages of a room.
+pub async fn room_handler(Path(name): Path<String>) -> Response {
+    if !is_room(&name) {
+        return room_info_error_response(RoomInfoError::NotARoom);
+    }
+    match (get_topic(&name), get_pins(&name)) {
+        (Ok(topic), Ok(pins)) => Json(RoomInfo { name, topic, pins }).into_response(),
+        (Err(e), _) | (_, Err(e)) => room_info_error_response(database_error(e)),
+    }
+}
+
+/// `PUT /api/rooms/:name/topic` with `{"topic": "..."}`, an empty topic removes it.
+pub async fn topic_handler(Path(name): Path<String>, Json(request): Json<TopicRequest>) -> Response {
+    match change_topic(&name, Some(&request.topic), WEB_UI).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => room_info_error_response(error),
+    }
+}
+
+/// `POST /api/rooms/:name/pins` with `{"message_id": 42}`.
+pub async fn pin_handler(Path(name): Path<String>, Json(request): Json<PinRequest>) -> Response {
+    match pin(&name, request.message_id, WEB_UI).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => room_info_error_response(error),
+    }
+}
+
+/// `DELETE /api/rooms/:name/pins/:id`.
+pub async fn unpin_handler(Path((name, message_id)): Path<(String, i64)>) -> Response {
+    match unpin(&name, message_id, WEB_UI).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error_response(StatusCode::NOT_FOUND, "message is not pinned"),
+        Err(error) => room_info_error_response(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_message_to_db, seed_test_users};
+
+    #[tokio::test]
+    async fn test_topics_and_pins() {
+        seed_test_users(&["alice"]);
+        let id = add_message_to_db(100, "alice", "global", "read the rules", None).unwrap();
+        let direct = add_message_to_db(200, "alice", "bob", "hi", None).unwrap();
+
+        assert!(is_room("global"));
+        assert!(!is_room("calculators"));
+        assert!(!is_room("alice"));
+        assert_eq!(change_topic("alice", Some("hi"), "mod").await, Err(RoomInfoError::NotARoom));
+
+        change_topic("global", Some("Be\nnice"), "mod").await.unwrap();
+        assert_eq!(get_topic("global").unwrap().unwrap().topic, "Be nice");
+        change_topic("global", Some(""), "mod").await.unwrap();
+        assert_eq!(get_topic("global").unwrap(), None);
+
+        assert_eq!(pin("global", id, "mod").await, Ok(true));
+        assert_eq!(pin("global", id, "mod").await, Ok(false));
+        assert_eq!(pin("global", direct, "mod").await, Err(RoomInfoError::NotInRoom));
+        assert_eq!(get_pins("global").unwrap()[0].id, id);
+        assert_eq!(unpin("global", id, "mod").await, Ok(true));
+        assert_eq!(unpin("global", id, "mod").await, Ok(false));
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::needless_return)]
 pub fn validate_username(username: &str) -> bool {
     if username.len() < 3 || username.len() > 18 {
         return false;
@@ -10,6 +11,7 @@ pub fn validate_username(username: &str) -> bool {
     return true;
 }
 
+#[allow(clippy::needless_return)]
 pub fn validate_session_token(session_token: &str) -> bool {
     if session_token.len() != 256 {
         return false;
@@ -21,6 +23,21 @@ pub fn validate_session_token(session_token: &str) -> bool {
     return true;
 }
 
+/// Room names are a `#` followed by what would be a valid username, so they never clash with a user.
+pub fn validate_room_name(name: &str) -> bool {
+    name.strip_prefix('#').is_some_and(validate_username)
+}
+
+/// A reaction is a short emoji or word, without the separators of the REACTIONS frame.
+pub fn validate_reaction(reaction: &str) -> bool {
+    let length = reaction.chars().count();
+    if length == 0 || length > 8 {
+        return false;
+    }
+
+    !reaction.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, ':' | ',' | '='))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,4 +76,26 @@ mod tests {
         assert!(!validate_username("test-user12345678901"));
         assert!(!validate_username("test_user12345678901"));
     }
+
+    #[test]
+    fn test_validate_room_name() {
+        assert!(validate_room_name("#lobby"));
+        assert!(validate_room_name("#test.room-1"));
+        assert!(!validate_room_name("lobby"));
+        assert!(!validate_room_name("#"));
+        assert!(!validate_room_name("##lobby"));
+        assert!(!validate_room_name("global"));
+    }
+
+    #[test]
+    fn test_validate_reaction() {
+        assert!(validate_reaction("+1"));
+        assert!(validate_reaction("ok"));
+        assert!(validate_reaction("\u{1f44d}"));
+        assert!(!validate_reaction(""));
+        assert!(!validate_reaction("too-long-1"));
+        assert!(!validate_reaction("a b"));
+        assert!(!validate_reaction("a:b"));
+        assert!(!validate_reaction("a=1,b"));
+    }
 }
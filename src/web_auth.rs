@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::{
+    extract::{ConnectInfo, Json, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::config::WebConfig;
+
+const SESSION_COOKIE: &str = "netchat_session";
+const CSRF_HEADER: &str = "x-csrf-token";
+
+struct WebSession {
+    csrf_token: String,
+    expires_at: i64,
+}
+
+struct FailedLogins {
+    count: u32,
+    last_failure: i64,
+}
+
+/// Credentials, sessions and login throttling of the web UI.
+pub struct WebAuth {
+    enabled: bool,
+    username: String,
+    password_hash: String,
+    api_token_hashes: Vec<Vec<u8>>,
+    session_timeout: i64,
+    max_login_attempts: u32,
+    lockout_duration: i64,
+    sessions: Mutex<HashMap<String, WebSession>>,
+    failed_logins: Mutex<HashMap<String, FailedLogins>>,
+}
+
+enum Principal {
+    ApiToken,
+    Session { csrf_token: String },
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    authenticated: bool,
+    authentication_required: bool,
+    csrf_token: Option<String>,
+}
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+fn digest(value: &str) -> Vec<u8> {
+    Sha256::digest(value.as_bytes()).to_vec()
+}
+
+fn random_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 48)
+}
+
+impl WebAuth {
+    /// Prefers `password_hash` from the config, the plaintext password is hashed at startup otherwise.
+    pub fn new(config: &WebConfig) -> Result<Self, argon2::password_hash::Error> {
+        let password_hash = if config.password_hash.is_empty() {
+            hash_password(&config.password)?
+        } else {
+            PasswordHash::new(&config.password_hash)?;
+            config.password_hash.clone()
+        };
+
+        Ok(WebAuth {
+            enabled: config.authentication,
+            username: config.username.clone(),
+            password_hash,
+            api_token_hashes: config.api_tokens.iter().map(|token| digest(token)).collect(),
+            session_timeout: config.session_timeout as i64,
+            max_login_attempts: config.max_login_attempts,
+            lockout_duration: config.lockout_duration as i64,
+            sessions: Mutex::new(HashMap::new()),
+            failed_logins: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn verify_credentials(&self, username: &str, password: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(&self.password_hash) else {
+            return false;
+        };
+        // always verify the password so a wrong username takes as long as a wrong password
+        let password_ok = Argon2::default().verify_password(password.as_bytes(), &hash).is_ok();
+        password_ok && digest(username) == digest(&self.username)
+    }
+
+    fn locked_out_for(&self, client: &str) -> Option<i64> {
+        let failed_logins = self.failed_logins.lock().unwrap();
+        let failures = failed_logins.get(client)?;
+        let remaining = failures.last_failure + self.lockout_duration - Utc::now().timestamp();
+        (failures.count >= self.max_login_attempts && remaining > 0).then_some(remaining)
+    }
+
+    fn record_failed_login(&self, client: &str) {
+        let now = Utc::now().timestamp();
+        let mut failed_logins = self.failed_logins.lock().unwrap();
+        // failures older than the lockout don't count anymore, forget them so every address ever seen isn't kept
+        failed_logins.retain(|_, failures| now - failures.last_failure <= self.lockout_duration);
+        let failures = failed_logins.entry(client.to_string()).or_insert(FailedLogins { count: 0, last_failure: now });
+        failures.count += 1;
+        failures.last_failure = now;
+    }
+
+    fn create_session(&self) -> (String, String) {
+        let session_id = random_token();
+        let csrf_token = random_token();
+        let now = Utc::now().timestamp();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(session_id.clone(), WebSession {
+            csrf_token: csrf_token.clone(),
+            expires_at: now + self.session_timeout,
+        });
+        (session_id, csrf_token)
+    }
+
+    fn authorize(&self, headers: &HeaderMap) -> Option<Principal> {
+        if let Some(token) = bearer_token(headers) {
+            let token_hash = digest(token);
+            return self.api_token_hashes.contains(&token_hash).then_some(Principal::ApiToken);
+        }
+
+        let session_id = session_cookie(headers)?;
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(session_id)?;
+        (session.expires_at > Utc::now().timestamp()).then(|| Principal::Session {
+            csrf_token: session.csrf_token.clone(),
+        })
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?
+        .to_str().ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers.get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+fn csrf_matches(headers: &HeaderMap, csrf_token: &str) -> bool {
+    headers.get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| digest(value) == digest(csrf_token))
+}
+
+fn client_key(connect_info: &Option<ConnectInfo<SocketAddr>>) -> String {
+    connect_info.as_ref()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+pub(crate) fn error_response(status: StatusCode, error: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": error }))).into_response()
+}
+
+/// Guards the API routes. Read-only requests are open when `web.authentication` is off,
+/// anything that changes state always needs a login or an API token.
+/// Cookie sessions must echo their CSRF token in the `X-CSRF-Token` header on unsafe methods.
+pub async fn require_auth(State(auth): State<Arc<WebAuth>>, request: Request, next: Next) -> Response {
+    let safe_method = matches!(*request.method(), Method::GET | Method::HEAD);
+    if safe_method && !auth.enabled {
+        return next.run(request).await;
+    }
+
+    match auth.authorize(request.headers()) {
+        Some(Principal::ApiToken) => next.run(request).await,
+        Some(Principal::Session { csrf_token }) => {
+            if safe_method || csrf_matches(request.headers(), &csrf_token) {
+                next.run(request).await
+            } else {
+                error_response(StatusCode::FORBIDDEN, "invalid csrf token")
+            }
+        }
+        None => error_response(StatusCode::UNAUTHORIZED, "unauthorized"),
+    }
+}
+
+pub async fn login_handler(
+    State(auth): State<Arc<WebAuth>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(login): Json<LoginRequest>,
+) -> Response {
+    let client = client_key(&connect_info);
+    if let Some(remaining) = auth.locked_out_for(&client) {
+        tracing::warn!(target: "webserver", "Rejected login from locked out client {}", client);
+        let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, "too many failed logins");
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(remaining));
+        return response;
+    }
+
+    if !auth.verify_credentials(&login.username, &login.password) {
+        tracing::warn!(target: "webserver", "Failed web UI login from {}", client);
+        auth.record_failed_login(&client);
+        return error_response(StatusCode::UNAUTHORIZED, "invalid credentials");
+    }
+
+    auth.failed_logins.lock().unwrap().remove(&client);
+    let (session_id, csrf_token) = auth.create_session();
+    tracing::info!(target: "webserver", "Web UI login from {}", client);
+
+    let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}", SESSION_COOKIE, session_id, auth.session_timeout);
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(SessionInfo { authenticated: true, authentication_required: auth.enabled, csrf_token: Some(csrf_token) }),
+    ).into_response()
+}
+
+pub async fn logout_handler(State(auth): State<Arc<WebAuth>>, headers: HeaderMap) -> Response {
+    let Some(Principal::Session { csrf_token }) = auth.authorize(&headers) else {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    };
+    if !csrf_matches(&headers, &csrf_token) {
+        return error_response(StatusCode::FORBIDDEN, "invalid csrf token");
+    }
+    if let Some(session_id) = session_cookie(&headers) {
+        auth.sessions.lock().unwrap().remove(session_id);
+    }
+
+    let cookie = format!("{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0", SESSION_COOKIE);
+    ([(header::SET_COOKIE, cookie)], StatusCode::NO_CONTENT).into_response()
+}
+
+/// Lets the dashboard find out whether it has to show the login form.
+pub async fn session_handler(State(auth): State<Arc<WebAuth>>, headers: HeaderMap) -> Json<SessionInfo> {
+    let csrf_token = match auth.authorize(&headers) {
+        Some(Principal::Session { csrf_token }) => Some(csrf_token),
+        _ => None,
+    };
+    Json(SessionInfo {
+        authenticated: csrf_token.is_some(),
+        authentication_required: auth.enabled,
+        csrf_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_config;
+
+    #[test]
+    fn test_failed_logins_are_forgotten_after_the_lockout() {
+        let auth = WebAuth::new(&get_config().unwrap().web).unwrap();
+        let long_ago = Utc::now().timestamp() - auth.lockout_duration - 1;
+        auth.failed_logins.lock().unwrap().insert("10.0.0.1".to_string(), FailedLogins { count: 3, last_failure: long_ago });
+        assert!(auth.locked_out_for("10.0.0.1").is_none());
+
+        auth.record_failed_login("10.0.0.2");
+        let failed_logins = auth.failed_logins.lock().unwrap();
+        assert!(!failed_logins.contains_key("10.0.0.1"));
+        assert_eq!(failed_logins["10.0.0.2"].count, 1);
+    }
+}
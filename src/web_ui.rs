@@ -1,24 +1,43 @@
 use axum::{
     extract::Json,
-    http::StatusCode,
+    http::{header, StatusCode},
+    middleware,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{delete, get, post, put},
     Router,
 };
 use serde::Serialize;
-use crate::state::{get_active_connections, get_active_users};
+use crate::state::{get_active_connections, get_active_users, get_uptime};
 use crate::config::Config;
 use std::net::SocketAddr;
 use std::str::FromStr;
-use axum_server::Server;
+use std::sync::Arc;
+use axum_server::{Handle, Server};
 use rusqlite::Connection;
-use crate::db::get_db_conn;
+use crate::db::{count_registered_users, get_db_conn};
+use crate::metrics::render_metrics;
+use crate::events::{self, events_handler};
+use crate::archive::{export_handler, messages_handler};
+use crate::stats::{message_series_handler, top_posters_handler};
+use crate::profiles::user_handler;
+use crate::rooms::rooms_handler;
+use crate::topics::{pin_handler, room_handler, topic_handler, unpin_handler};
+use crate::welcome::{update_welcome_handler, welcome_handler};
+use crate::web_auth::{login_handler, logout_handler, require_auth, session_handler, WebAuth};
+use crate::admin::{
+    connection_handler, connections_handler, kick_connection_handler, kick_unauthenticated_handler,
+    kick_user_handler, notice_handler,
+};
 
 #[derive(Serialize)]
 struct ServerInfo {
     total_messages: usize,
     total_time_online: usize,
     uptime: usize,
+    online_users: usize,
+    peak_concurrent_users: usize,
+    peak_concurrent_users_at: Option<i64>,
+    registered_users: i64,
 }
 
 #[derive(Serialize)]
@@ -65,14 +84,23 @@ async fn index_handler() -> Html<&'static str> {
 async fn info_handler() -> Result<Json<ServerInfo>, DatabaseError> {
     let conn = get_db_conn().map_err(|_| DatabaseError)?;
 
-    let total_messages = get_value_from_db(&conn, "messages_sent").unwrap();
-    let total_time_online = get_value_from_db(&conn, "total_time_online").unwrap();
-    let uptime = 0; // TODO: implement this
+    // counters that were never written yet count as zero
+    let total_messages = get_value_from_db(&conn, "messages_sent").unwrap_or_default();
+    let total_time_online = get_value_from_db(&conn, "total_time_online").unwrap_or_default();
+    let peak_concurrent_users = get_value_from_db(&conn, "peak_concurrent_users").unwrap_or_default();
+    let peak_concurrent_users_at = get_value_from_db(&conn, "peak_concurrent_users_at").ok();
+    let registered_users = count_registered_users().map_err(|_| DatabaseError)?;
+    let uptime = get_uptime() as usize;
+    let online_users = get_active_users().read().await.len();
 
     Ok(Json(ServerInfo {
         total_messages,
         total_time_online,
         uptime,
+        online_users,
+        peak_concurrent_users,
+        peak_concurrent_users_at,
+        registered_users,
     }))
 }
 
@@ -109,30 +137,250 @@ async fn active_users_handler() -> Json<Vec<String>> {
     Json(active_users_list)
 }
 
-pub async fn run_web_ui(
-    config: Config,
-) {
-    let app = Router::new()
-        .route("/", get(index_handler))
+async fn metrics_handler() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], render_metrics().await)
+}
+
+pub fn bind_web_listener(config: &Config) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind((config.web.host.as_str(), config.web.port))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+pub fn build_router(auth: Arc<WebAuth>) -> Router {
+    // every route in here goes through the authentication middleware
+    let protected = Router::new()
         .route("/api/info", get(info_handler))
         .route("/api/users", get(users_handler))
         .route("/api/active-connections", get(active_connections_handler))
-        .route("/api/active-users", get(active_users_handler));
+        .route("/api/active-users", get(active_users_handler))
+        .route("/api/connections", get(connections_handler))
+        .route("/api/connections/kick-unauthenticated", post(kick_unauthenticated_handler))
+        .route("/api/connections/:id", get(connection_handler))
+        .route("/api/connections/:id/kick", post(kick_connection_handler))
+        .route("/api/users/:username", get(user_handler))
+        .route("/api/users/:username/kick", post(kick_user_handler))
+        .route("/api/notice", post(notice_handler))
+        .route("/api/welcome", get(welcome_handler).put(update_welcome_handler))
+        .route("/api/rooms", get(rooms_handler))
+        .route("/api/rooms/:name", get(room_handler))
+        .route("/api/rooms/:name/topic", put(topic_handler))
+        .route("/api/rooms/:name/pins", post(pin_handler))
+        .route("/api/rooms/:name/pins/:id", delete(unpin_handler))
+        .route("/api/events", get(events_handler))
+        .route("/api/messages", get(messages_handler))
+        .route("/api/messages/export", get(export_handler))
+        .route("/api/stats/messages", get(message_series_handler))
+        .route("/api/stats/top-posters", get(top_posters_handler))
+        .route("/metrics", get(metrics_handler))
+        .route_layer(middleware::from_fn_with_state(auth.clone(), require_auth));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.web.port));
+    Router::new()
+        .route("/", get(index_handler))
+        .route("/auth/login", post(login_handler))
+        .route("/auth/logout", post(logout_handler))
+        .route("/auth/session", get(session_handler))
+        .merge(protected)
+        .with_state(auth)
+}
+
+pub async fn run_web_ui(
+    config: Config,
+    listener: std::net::TcpListener,
+    handle: Handle,
+) {
+    let auth = match WebAuth::new(&config.web) {
+        Ok(auth) => Arc::new(auth),
+        Err(e) => {
+            tracing::error!(target: "webserver", "Invalid web UI credentials, not starting the web server: {}", e);
+            return;
+        }
+    };
+    if !config.web.authentication {
+        tracing::warn!(target: "webserver", "Web UI authentication is disabled, read-only endpoints are open to anyone who can reach them");
+    }
+    events::set_redact_messages(config.web.redact_events);
+    let app = build_router(auth);
 
     tracing::info!(target: "webserver", "Starting web server on {}:{}", config.web.host, config.web.port);
-    Server::bind(addr)
-        .serve(app.into_make_service())
+    Server::from_tcp(listener)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
-
 #[cfg(test)]
 mod tests {
     use crate::db::init_db;
+    use crate::config::get_config;
     use super::*;
+    use axum::body::Body;
+    use axum::http::{header, Request};
+    use tower::ServiceExt;
+
+    const PROTECTED_ROUTES: [(&str, &str); 25] = [
+        ("GET", "/api/info"),
+        ("GET", "/api/users"),
+        ("GET", "/api/users/alice"),
+        ("POST", "/api/users/alice/kick"),
+        ("GET", "/api/active-connections"),
+        ("GET", "/api/active-users"),
+        ("GET", "/api/connections"),
+        ("POST", "/api/connections/kick-unauthenticated"),
+        ("GET", "/api/connections/1"),
+        ("POST", "/api/connections/1/kick"),
+        ("POST", "/api/notice"),
+        ("GET", "/api/events"),
+        ("GET", "/api/messages"),
+        ("GET", "/api/messages/export?format=csv"),
+        ("GET", "/api/rooms"),
+        ("GET", "/api/rooms/global"),
+        ("PUT", "/api/rooms/global/topic"),
+        ("POST", "/api/rooms/global/pins"),
+        ("DELETE", "/api/rooms/global/pins/1"),
+        ("GET", "/api/welcome"),
+        ("PUT", "/api/welcome"),
+        ("GET", "/api/stats/messages"),
+        ("GET", "/api/stats/top-posters"),
+        ("GET", "/metrics"),
+        ("HEAD", "/api/info"),
+    ];
+
+    fn api_request(method: &str, uri: &str) -> axum::http::request::Builder {
+        Request::builder().method(method).uri(uri)
+    }
+
+    fn test_router() -> Router {
+        let config = get_config().unwrap();
+        build_router(Arc::new(WebAuth::new(&config.web).unwrap()))
+    }
+
+    async fn login(app: &Router, password: &str) -> axum::response::Response {
+        let request = Request::post("/auth/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({ "username": "admin", "password": password }).to_string()))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_routes_reject_unauthenticated_requests() {
+        let app = test_router();
+        for (method, route) in PROTECTED_ROUTES {
+            let response = app.clone().oneshot(api_request(method, route).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} {} is not protected", method, route);
+
+            let request = api_request(method, route)
+                .header(header::AUTHORIZATION, "Bearer wrong-token")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} {} accepts a wrong token", method, route);
+
+            let request = api_request(method, route)
+                .header(header::COOKIE, "netchat_session=forged")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} {} accepts a forged session", method, route);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_routes_reject_sessions_without_csrf_token() {
+        let app = test_router();
+        let response = login(&app, "admin").await;
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+
+        for (method, route) in PROTECTED_ROUTES.into_iter().filter(|(method, _)| !matches!(*method, "GET" | "HEAD")) {
+            let request = api_request(method, route)
+                .header(header::COOKIE, &cookie)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{} {} accepts a session without csrf token", method, route);
+
+            let request = api_request(method, route)
+                .header(header::COOKIE, &cookie)
+                .header("x-csrf-token", "wrong")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{} {} accepts a wrong csrf token", method, route);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_token_and_session_access() {
+        init_db().unwrap();
+        let app = test_router();
+
+        let request = Request::get("/api/active-users")
+            .header(header::AUTHORIZATION, "Bearer test-api-token")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+
+        let response = login(&app, "admin").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+        let request = Request::get("/api/info")
+            .header(header::COOKIE, &cookie)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+
+        // unsafe methods need the csrf token
+        let request = Request::post("/auth/logout")
+            .header(header::COOKIE, &cookie)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes() {
+        let app = test_router();
+        let admin_request = |method: &str, uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, "Bearer test-api-token")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        // changing state is never open, even when only reading would be
+        let request = Request::post("/api/connections/kick-unauthenticated").body(Body::empty()).unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let request = admin_request("GET", "/api/connections/0", serde_json::Value::Null);
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+        let request = admin_request("POST", "/api/connections/0/kick", serde_json::json!({ "reason": "test" }));
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+        let request = admin_request("POST", "/api/users/nobody/kick", serde_json::Value::Null);
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        let request = admin_request("POST", "/api/notice", serde_json::json!({ "target": "user", "message": "hi" }));
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        let request = admin_request("POST", "/api/notice", serde_json::json!({ "target": "room", "name": "nowhere", "message": "hi" }));
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_login_lockout() {
+        let app = test_router();
+        for _ in 0..3 {
+            assert_eq!(login(&app, "wrong").await.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = login(&app, "admin").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+    }
 
     #[test]
     fn test_get_value_from_db() {
@@ -0,0 +1,294 @@
+use std::sync::RwLock;
+use axum::{
+    extract::Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::admin::publish_moderation;
+use crate::config::WelcomeConfig;
+use crate::conn_handler::{send_to_clients, write_to_socket};
+use crate::db::{acknowledge_rules, get_acknowledged_rules, get_server_setting, set_server_setting};
+use crate::state::{get_active_users, sends_events, ClientSocket};
+use crate::web_auth::error_response;
+
+// server_data keys of the texts changed through the web UI
+const MOTD_KEY: &str = "motd";
+const RULES_KEY: &str = "rules";
+const MAX_TEXT_LENGTH: usize = 2000;
+// protocol 1 clients get no RULES frames, but they do show server notices
+const RULES_NOTICE: &str = "SERVER_NOTICE:This server has rules, read them with ?rules and send ACCEPT_RULES to accept them\n";
+
+/// What users are greeted with after logging in.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Welcome {
+    pub server_name: String,
+    pub motd: String,
+    pub rules: String,
+}
+
+lazy_static! {
+    static ref WELCOME: RwLock<Welcome> = RwLock::new(Welcome::default());
+}
+
+fn current() -> Welcome {
+    WELCOME.read().unwrap().clone()
+}
+
+/// Loads `[welcome]` from the config, with the texts changed through the web UI taking precedence.
+pub fn init(config: &WelcomeConfig) {
+    let stored = |key: &str, default: &str| match get_server_setting(key) {
+        Ok(value) => value.unwrap_or_else(|| default.to_string()),
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to load the {} setting: {}", key, e);
+            default.to_string()
+        }
+    };
+    *WELCOME.write().unwrap() = Welcome {
+        server_name: config.server_name.clone(),
+        motd: stored(MOTD_KEY, &config.motd),
+        rules: stored(RULES_KEY, &config.rules),
+    };
+}
+
+/// Fills in `{server_name}`, `{online}` and `{username}`, in one pass so values are never expanded again.
+pub fn render(template: &str, server_name: &str, online: usize, username: &str) -> String {
+    let online = online.to_string();
+    let values = [("{server_name}", server_name), ("{online}", online.as_str()), ("{username}", username)];
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                rendered.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// the protocol is line based, every line of a text gets a frame of its own
+fn frames(verb: &str, text: &str) -> Vec<String> {
+    text.lines().map(|line| format!("{}:{}\n", verb, line.trim_end())).collect()
+}
+
+fn rules_frames(welcome: &Welcome, online: usize, username: &str) -> Vec<String> {
+    let mut frames = frames("RULES", &render(&welcome.rules, &welcome.server_name, online, username));
+    frames.push("RULES_REQUIRED\n".to_string());
+    frames
+}
+
+fn has_rules(welcome: &Welcome) -> bool {
+    !welcome.rules.trim().is_empty()
+}
+
+// acknowledgements are stored against a hash of the rules, so changing them asks everyone again
+fn rules_hash(rules: &str) -> String {
+    Sha256::digest(rules.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Whether `username` may post: there are no rules, or they accepted the current ones.
+pub fn rules_accepted(username: &str) -> bool {
+    let welcome = current();
+    if !has_rules(&welcome) {
+        return true;
+    }
+    match get_acknowledged_rules(username) {
+        Ok(accepted) => accepted == Some(rules_hash(&welcome.rules)),
+        Err(e) => {
+            tracing::error!(target: "db", "Failed to load the rules acknowledgement of {}: {}", username, e);
+            false
+        }
+    }
+}
+
+/// Handles `ACCEPT_RULES`, returns the reply for the client.
+pub fn accept_rules(username: &str) -> &'static str {
+    let welcome = current();
+    if has_rules(&welcome) {
+        if let Err(e) = acknowledge_rules(username, &rules_hash(&welcome.rules), Utc::now().timestamp()) {
+            tracing::error!(target: "db", "Failed to store the rules acknowledgement of {}: {}", username, e);
+            return "ACCEPT_RULES_FAILED\n";
+        }
+        tracing::info!(target: "server", "{} accepted the rules", username);
+    }
+    "RULES_ACCEPTED\n"
+}
+
+async fn online_users() -> usize {
+    get_active_users().read().await.len()
+}
+
+/// Sends the MOTD, and the rules if `username` has yet to accept them, to a protocol 2 client that just logged in.
+/// Protocol 1 clients only get a notice that points them to `?rules`, and only while there are rules to accept.
+pub async fn send_welcome(username: &str, protocol_version: u8, socket: &mut ClientSocket<'_>) {
+    if !sends_events(protocol_version) {
+        if !rules_accepted(username) {
+            let _ = write_to_socket(socket, RULES_NOTICE.as_bytes()).await;
+        }
+        return;
+    }
+    let welcome = current();
+    let online = online_users().await;
+    let mut welcome_frames = frames("MOTD", &render(&welcome.motd, &welcome.server_name, online, username));
+    if !rules_accepted(username) {
+        welcome_frames.extend(rules_frames(&welcome, online, username));
+    }
+    for frame in welcome_frames {
+        let _ = write_to_socket(socket, frame.as_bytes()).await;
+    }
+}
+
+/// The answer to `?motd`.
+pub async fn motd_text(username: &str) -> String {
+    let welcome = current();
+    if welcome.motd.trim().is_empty() {
+        return format!("{} has no message of the day", welcome.server_name);
+    }
+    render(&welcome.motd, &welcome.server_name, online_users().await, username)
+}
+
+/// The answer to `?rules`.
+pub async fn rules_text(username: &str) -> String {
+    let welcome = current();
+    if !has_rules(&welcome) {
+        return format!("{} has no rules", welcome.server_name);
+    }
+    let rules = render(&welcome.rules, &welcome.server_name, online_users().await, username);
+    if rules_accepted(username) {
+        format!("Rules of {}:\n{}", welcome.server_name, rules)
+    } else {
+        format!("Rules of {}:\n{}\nSend ACCEPT_RULES to accept them", welcome.server_name, rules)
+    }
+}
+
+// the new rules go to everyone online, nobody has accepted them yet
+async fn announce_rules(welcome: &Welcome) {
+    let active_users = get_active_users();
+    let active_users = active_users.read().await;
+    let online = active_users.len();
+    for (username, sessions) in active_users.iter() {
+        let (sessions, protocol_1_sessions): (Vec<_>, Vec<_>) = sessions.iter().cloned().partition(|client| client.listener.sends_events());
+        send_to_clients(&sessions, &rules_frames(welcome, online, username).concat());
+        send_to_clients(&protocol_1_sessions, RULES_NOTICE);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WelcomeRequest {
+    motd: Option<String>,
+    rules: Option<String>,
+}
+
+fn normalize(text: &str) -> String {
+    text.replace("\r\n", "\n").trim_end().to_string()
+}
+
+/// `GET /api/welcome`, the server name, MOTD and rules as templates.
+pub async fn welcome_handler() -> Json<Welcome> {
+    Json(current())
+}
+
+/// `PUT /api/welcome` with `{"motd": "...", "rules": "..."}`, fields left out stay as they are and empty ones remove the text.
+pub async fn update_welcome_handler(Json(request): Json<WelcomeRequest>) -> Response {
+    let motd = request.motd.as_deref().map(normalize);
+    let rules = request.rules.as_deref().map(normalize);
+    if [&motd, &rules].into_iter().flatten().any(|text| text.chars().count() > MAX_TEXT_LENGTH) {
+        return error_response(StatusCode::BAD_REQUEST, "text is too long");
+    }
+
+    for (key, text) in [(MOTD_KEY, &motd), (RULES_KEY, &rules)] {
+        let Some(text) = text else {
+            continue;
+        };
+        if let Err(e) = set_server_setting(key, Some(text)) {
+            tracing::error!(target: "db", "Failed to store the {} setting: {}", key, e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+        tracing::info!(target: "admin", "Changed the {} through the web UI", key);
+        publish_moderation(key, "everyone".to_string(), text);
+    }
+
+    let (welcome, rules_changed) = {
+        let mut welcome = WELCOME.write().unwrap();
+        if let Some(motd) = motd {
+            welcome.motd = motd;
+        }
+        let rules_changed = rules.as_ref().is_some_and(|rules| *rules != welcome.rules);
+        if let Some(rules) = rules {
+            welcome.rules = rules;
+        }
+        (welcome.clone(), rules_changed)
+    };
+    if rules_changed && has_rules(&welcome) {
+        announce_rules(&welcome).await;
+    }
+    Json(welcome).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::init_db;
+
+    #[test]
+    fn test_render() {
+        let motd = "Welcome to {server_name}, {username}! {online} online {unknown}";
+        assert_eq!(render(motd, "netchat", 3, "alice"), "Welcome to netchat, alice! 3 online {unknown}");
+        // values are not expanded again
+        assert_eq!(render("{server_name}", "{username}", 0, "alice"), "{username}");
+        assert_eq!(frames("MOTD", "one\r\n\ntwo\n"), vec!["MOTD:one\n", "MOTD:\n", "MOTD:two\n"]);
+    }
+
+    #[test]
+    fn test_rules_acknowledgement() {
+        init_db().unwrap();
+        let rules = "Be nice";
+        assert_eq!(get_acknowledged_rules("alice").unwrap(), None);
+        acknowledge_rules("alice", &rules_hash(rules), 100).unwrap();
+        assert_eq!(get_acknowledged_rules("alice").unwrap(), Some(rules_hash(rules)));
+        assert_ne!(rules_hash(rules), rules_hash("Be nice, or else"));
+
+        assert_eq!(get_server_setting(MOTD_KEY).unwrap(), None);
+        set_server_setting(MOTD_KEY, Some("Hi {username}")).unwrap();
+        assert_eq!(get_server_setting(MOTD_KEY).unwrap().as_deref(), Some("Hi {username}"));
+        set_server_setting(MOTD_KEY, None).unwrap();
+        assert_eq!(get_server_setting(MOTD_KEY).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_protocol_1_clients_are_told_about_the_rules() {
+        use std::sync::Arc;
+        use tokio::io::AsyncReadExt;
+        use crate::state::{Client, ListenerOptions, PeerAddr, TEST_STATE_LOCK};
+
+        init_db().unwrap();
+        // the rules are global, every other test expects there to be none
+        let _state = TEST_STATE_LOCK.lock().await;
+        let previous_rules = std::mem::replace(&mut WELCOME.write().unwrap().rules, "Be nice".to_string());
+        let listener = Arc::new(ListenerOptions { name: "chat".to_string(), tls: false, require_password: false, protocol_version: 1 });
+        let (stream, mut peer) = tokio::io::duplex(1024);
+        let client = Client::new(1, PeerAddr::Unix, listener, Box::new(stream));
+
+        send_welcome("newcomer", 1, &mut client.lock().await).await;
+        acknowledge_rules("newcomer", &rules_hash("Be nice"), 100).unwrap();
+        send_welcome("newcomer", 1, &mut client.lock().await).await;
+        drop(client);
+        WELCOME.write().unwrap().rules = previous_rules;
+
+        let mut received = String::new();
+        peer.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, RULES_NOTICE);
+    }
+}